@@ -78,6 +78,21 @@ mod platform {
 			Ok((size.ws_col, size.ws_row))
 		}
 	}
+
+	/// Wakes whenever the terminal is resized.
+	pub struct Resizes(tokio::signal::unix::Signal);
+
+	impl Resizes {
+		pub fn new() -> io::Result<Self> {
+			use tokio::signal::unix::{ signal, SignalKind };
+
+			Ok(Self(signal(SignalKind::window_change())?))
+		}
+
+		pub async fn recv(&mut self) {
+			self.0.recv().await;
+		}
+	}
 }
 
 #[cfg(target_os = "windows")]
@@ -193,6 +208,20 @@ mod platform {
 
 		Ok((info.dwSize.X as u16, info.dwSize.Y as u16))
 	}
+
+	/// Wakes whenever the terminal may have been resized. The console has no
+	/// resize signal, so this checks a few times a second.
+	pub struct Resizes(tokio::time::Interval);
+
+	impl Resizes {
+		pub fn new() -> io::Result<Self> {
+			Ok(Self(tokio::time::interval(std::time::Duration::from_millis(250))))
+		}
+
+		pub async fn recv(&mut self) {
+			self.0.tick().await;
+		}
+	}
 }
 
 pub use platform::*;
@@ -0,0 +1,66 @@
+use crate::websocket::{ connect, exit_status, Connection, ConnectionSettings, ExitStatus, Input, Output };
+
+use std::io;
+use std::process::exit;
+
+use tokio::io::{ AsyncReadExt, AsyncWrite, AsyncWriteExt };
+
+const BUFFER_SIZE: usize = 8192;
+
+/// Runs `args` in the repl, and exits with its status once it finishes.
+pub async fn start(settings: ConnectionSettings, args: Vec<String>, env: Vec<(String, String)>) {
+	match run(settings, args, env).await {
+		Ok(status) => exit(status),
+		Err(err) => {
+			error!("{}", err);
+			exit(exit_status(&err));
+		},
+	}
+}
+
+async fn run(settings: ConnectionSettings, args: Vec<String>, env: Vec<(String, String)>) -> io::Result<i32> {
+	let session = connect(settings).await?;
+	let (tx, mut rx) = session.open(Connection::Exec(args, env))?;
+
+	let mut stdin = tokio::io::stdin();
+	let mut stdout = tokio::io::stdout();
+	let mut stderr = tokio::io::stderr();
+	let mut stdin_open = true;
+	let mut buffer = vec![ 0; BUFFER_SIZE ];
+
+	loop {
+		tokio::select! {
+			read = stdin.read(&mut buffer), if stdin_open => match read {
+				Ok(read) if read > 0 => {
+					let _ = tx.send(Input::Data(buffer[..read].to_vec()));
+				},
+				_ => {
+					stdin_open = false;
+					let _ = tx.send(Input::Eof);
+				},
+			},
+			output = rx.recv() => match output {
+				Some(Output::Data(data)) => write_flush(&mut stdout, &data).await?,
+				Some(Output::ErrorData(data)) => write_flush(&mut stderr, &data).await?,
+				Some(Output::Died(status)) => {
+					// a normal exit speaks for itself, but a signal may not leave any trace
+					if let ExitStatus::Signaled { .. } = status {
+						eprintln!("{}", status);
+					}
+
+					// so the server doesn't keep the session for us to resume
+					session.end().await;
+					return Ok(status.code())
+				},
+				Some(Output::Error(error)) => return Err(error.into()),
+				Some(Output::Closed) | None => return Err(io::Error::other("command closed by server")),
+				Some(_) => (),
+			},
+		}
+	}
+}
+
+async fn write_flush(writer: &mut (impl AsyncWrite + Unpin), data: &[u8]) -> io::Result<()> {
+	writer.write_all(data).await?;
+	writer.flush().await
+}
@@ -2,13 +2,20 @@
 #[macro_use] extern crate log;
 
 mod console;
+mod exec;
 mod portfwd;
+mod ports;
+mod sessions;
 mod shell;
+mod socks;
+mod table;
 mod websocket;
 
-use crate::websocket::{ Connection, ConnectionSettings, Repl };
+use crate::websocket::{ Connection, ConnectionSettings, Credentials, Repl };
 
-use std::env;
+use autobahn_protocol::keys;
+
+use std::{ env, fs };
 use std::io::{ self, Write };
 use std::process::exit;
 use std::str::FromStr;
@@ -17,31 +24,58 @@ use log::LevelFilter as LogLevelFilter;
 
 use simple_logger::SimpleLogger;
 
+use tokio::runtime::Builder;
+
 fn main() {
 	let matches = clap_app!(app =>
 		(name: env!("CARGO_PKG_NAME"))
 		(version: env!("CARGO_PKG_VERSION"))
 		(author: env!("CARGO_PKG_AUTHORS"))
 		(about: env!("CARGO_PKG_DESCRIPTION"))
-		(@setting ArgsNegateSubcommands)
 		(@setting ColoredHelp)
 		(@setting GlobalVersion)
-		(@setting SubcommandsNegateReqs)
 		(@setting SubcommandRequiredElseHelp)
-		(@arg REPL: +takes_value +required +global "Specify the repl to connect to")
-		(@arg KEY: -k --key +takes_value +global "Specify the key to authenticate with")
+		(@arg REPL: +takes_value +required "Specify the repl to connect to")
+		(@arg KEY: -k --key +takes_value +global conflicts_with[IDENTITY] "Specify the key to authenticate with")
+		(@arg IDENTITY: -i --identity +takes_value +global "Specify an Ed25519 private key file to authenticate with")
 		(@arg verbose: -v conflicts_with[trace] +global "Log more debug information to output")
 		(@arg very_verbose: --verbose conflicts_with[verbose] +global "Log even more debug information to output")
 		(@arg trace: --trace +hidden conflicts_with[very_verbose] +global "Log an excessive amount of debug information to output")
+		(@subcommand exec =>
+			(about: "Run a command in the repl without a terminal, like a local one")
+			(@setting ColoredHelp)
+			(@setting TrailingVarArg)
+			(@arg ENV: -e --env +takes_value +multiple number_of_values(1) "Pass a variable to the command, as NAME=VALUE or the NAME of one set here")
+			(@arg COMMAND: +required +multiple "Specify the command to run, and its arguments")
+		)
 		(@subcommand portfwd =>
-			(about: "Listen on a local port and forward to a port in the repl")
+			(about: "Listen on a local port and forward to a port in the repl, or the other way around")
 			(@setting ColoredHelp)
-			(@arg REMOTE: -r --remote +takes_value +required "Specify the remote port to forward to")
-			(@arg LOCAL: -l --local +takes_value "Specify the local port to listen on")
+			(@arg REMOTE: -r --remote +takes_value +required "Specify the remote [host:]port to forward to, or the port to listen on with --reverse")
+			(@arg LOCAL: -l --local +takes_value "Specify the local port to listen on, or the [host:]port to forward to with --reverse")
+			(@arg udp: -u --udp "Forward UDP datagrams instead of TCP connections")
+			(@arg reverse: -R --reverse conflicts_with[udp] "Listen in the repl and forward connections to this side")
+		)
+		(@subcommand ports =>
+			(about: "List the ports listening in the repl")
+			(@setting ColoredHelp)
+			(@arg json: --json "Print the listeners as JSON")
+		)
+		(@subcommand sessions =>
+			(about: "List the shells running in the repl, to attach to")
+			(@setting ColoredHelp)
+			(@arg json: --json "Print the sessions as JSON")
 		)
 		(@subcommand shell =>
 			(@setting ColoredHelp)
 			(about: "Open and connect to a remote shell in the repl")
+			(@arg ENV: -e --env +takes_value +multiple number_of_values(1) "Pass a variable to the shell, as NAME=VALUE or the NAME of one set here")
+			(@arg ATTACH: -a --attach +takes_value conflicts_with[ENV] "Attach to a running shell by its session ID instead of starting one")
+		)
+		(@subcommand socks =>
+			(about: "Run a local SOCKS5 proxy that connects to ports in the repl")
+			(@setting ColoredHelp)
+			(@arg LOCAL: -l --local +takes_value "Specify the local port to listen on")
 		)
 	).get_matches();
 
@@ -72,28 +106,80 @@ fn main() {
 		},
 	};
 
-	let key = match matches.value_of("KEY") {
-		Some(key) => key.to_string(),
-		_ => {
-			print!("Password: ");
-			let _ = io::stdout().flush();
-
-			let mut password = String::new();
-			if io::stdin().read_line(&mut password).is_ok() {
-				password
-			} else {
-				error!("io error");
+	let credentials = if let Some(identity) = matches.value_of("IDENTITY") {
+		let key = fs::read_to_string(identity)
+			.map_err(|error| error.to_string())
+			.and_then(|data| keys::parse_private_key(&data).map_err(|error| error.to_string()));
+
+		match key {
+			Ok(key) => Credentials::Identity(key),
+			Err(error) => {
+				error!("failed to load identity {}: {}", identity, error);
 				exit(1);
-			}
+			},
 		}
-	};
+	} else if let Some(key) = matches.value_of("KEY") {
+		Credentials::Key(key.to_string())
+	} else {
+		print!("Password: ");
+		let _ = io::stdout().flush();
 
-	let mut connection = ConnectionSettings {
-		connection: Connection::Shell,
-		repl, key,
+		let mut password = String::new();
+		if io::stdin().read_line(&mut password).is_ok() {
+			Credentials::Key(password.trim_end_matches(&[ '\r', '\n' ][..]).to_string())
+		} else {
+			error!("io error");
+			exit(1);
+		}
 	};
 
-	if let Some(matches) = matches.subcommand_matches("portfwd") {
+	let settings = ConnectionSettings { repl, credentials };
+
+	let runtime = Builder::new_current_thread()
+		.enable_all()
+		.build()
+		.unwrap_or_else(|error| {
+			error!("failed to start runtime: {}", error);
+			exit(1);
+		});
+
+	if let Some(matches) = matches.subcommand_matches("exec") {
+		let args = matches.values_of("COMMAND")
+			.unwrap()
+			.map(String::from)
+			.collect();
+		let env = client_env(&[ "LANG" ], matches.values_of("ENV").into_iter().flatten());
+
+		runtime.block_on(exec::start(settings, args, env));
+	} else if let Some(matches) = matches.subcommand_matches("portfwd") {
+		// a host outside the repl goes in front of the port, IPv6 ones in brackets
+		let (host, remote) = match matches.value_of("REMOTE").unwrap().rsplit_once(':') {
+			Some((host, port)) => (Some(host.trim_start_matches('[').trim_end_matches(']')), port),
+			None => (None, matches.value_of("REMOTE").unwrap()),
+		};
+		let remote = u16::from_str(remote)
+			.unwrap_or_else(|_| {
+				error!("failed to parse remote port");
+				exit(1);
+			});
+
+		if host.is_some() && (matches.is_present("udp") || matches.is_present("reverse")) {
+			error!("a remote host can only be given when forwarding TCP");
+			exit(1);
+		}
+
+		if matches.is_present("reverse") {
+			// a bare port is on this machine, and so is the same port by default
+			let target = match matches.value_of("LOCAL") {
+				Some(local) if u16::from_str(local).is_ok() => format!("localhost:{}", local),
+				Some(local) => local.to_string(),
+				None => format!("localhost:{}", remote),
+			};
+
+			runtime.block_on(portfwd::start_reverse(settings, remote, target));
+			return
+		}
+
 		let local = matches.value_of("LOCAL")
 			.and_then(|string| {
 				u16::from_str(string)
@@ -103,20 +189,73 @@ fn main() {
 						None
 					})
 			});
-		
-		let remote = u16::from_str(
-			matches.value_of("REMOTE")
-				.unwrap()
-		)
-			.unwrap_or_else(|_| {
-				error!("failed to parse remote port");
-				exit(1);
+
+		let connection = if let Some(host) = host {
+			Connection::Remote(host.to_string(), remote)
+		} else if matches.is_present("udp") {
+			Connection::UdpPort(remote)
+		} else {
+			Connection::Port(remote)
+		};
+
+		runtime.block_on(portfwd::start(settings, connection, local));
+	} else if let Some(matches) = matches.subcommand_matches("ports") {
+		runtime.block_on(ports::start(settings, matches.is_present("json")));
+	} else if let Some(matches) = matches.subcommand_matches("sessions") {
+		runtime.block_on(sessions::start(settings, matches.is_present("json")));
+	} else if let Some(matches) = matches.subcommand_matches("socks") {
+		let local = matches.value_of("LOCAL")
+			.and_then(|string| {
+				u16::from_str(string)
+					.ok()
+					.or_else(|| {
+						warn!("invalid local port argument, using default");
+						None
+					})
 			});
-		
-		connection.connection = Connection::Port(remote);
 
-		portfwd::start(connection, local);
+		runtime.block_on(socks::start(settings, local));
 	} else {
-		shell::start(connection);
+		let matches = matches.subcommand_matches("shell");
+		let connection = match matches.and_then(|matches| matches.value_of("ATTACH")) {
+			Some(id) => Connection::Attach(u32::from_str(id)
+				.unwrap_or_else(|_| {
+					error!("invalid session ID {:?}", id);
+					exit(1);
+				})),
+			None => {
+				let env = matches
+					.and_then(|matches| matches.values_of("ENV"))
+					.into_iter()
+					.flatten();
+
+				Connection::Shell(client_env(&[ "TERM", "LANG" ], env))
+			},
+		};
+
+		runtime.block_on(shell::start(settings, connection));
 	}
 }
+
+// the `defaults` that are set on this side, and whatever else was asked for
+fn client_env<'a>(defaults: &[&str], args: impl Iterator<Item = &'a str>) -> Vec<(String, String)> {
+	let mut vars = Vec::new();
+
+	for &name in defaults {
+		if let Ok(value) = env::var(name) {
+			vars.push((name.to_string(), value));
+		}
+	}
+
+	for arg in args {
+		match arg.split_once('=') {
+			Some((name, value)) => vars.push((name.to_string(), value.to_string())),
+			None => match env::var(arg) {
+				Ok(value) => vars.push((arg.to_string(), value)),
+				Err(_) => warn!("{} isn't set, not passing it on", arg),
+			},
+		}
+	}
+
+	vars
+}
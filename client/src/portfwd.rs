@@ -1,78 +1,221 @@
-use crate::websocket::{ connect, ConnectionSettings, Input, Output };
+use crate::websocket::{ connect, exit_status, Channel, Connection, ConnectionSettings, Input, Output };
 
-use std::thread;
-use std::net::{ Shutdown, TcpListener, TcpStream };
-use std::io::{ self, ErrorKind, Read, Write };
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
 use std::process::exit;
-use std::sync::mpsc::TryRecvError;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+use tokio::net::{ TcpListener, TcpStream, UdpSocket };
+use tokio::sync::mpsc::{ self, UnboundedReceiver, UnboundedSender };
+use tokio::time::{ self, Instant };
 
 const DEFAULT_PORT: u16 = 3325;
+const BUFFER_SIZE: usize = 8192;
+// so that no datagram a local peer sends is cut short
+const DATAGRAM_SIZE: usize = 65536;
+// how long a UDP peer can stay quiet before its session is closed
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub async fn start(settings: ConnectionSettings, connection: Connection, local: Option<u16>) {
+	let local = local.unwrap_or(DEFAULT_PORT);
+	let result = match connection {
+		Connection::UdpPort(_) => run_udp(settings, connection, local).await,
+		_ => run(settings, connection, local).await,
+	};
 
-pub fn start(connection: ConnectionSettings, port: Option<u16>) {
-	if let Err(err) = run(connection, port.unwrap_or(DEFAULT_PORT)) {
+	if let Err(err) = result {
 		error!("{}", err);
-		exit(1);
+		exit(exit_status(&err));
 	}
 }
 
-fn run(connection: ConnectionSettings, port: u16) -> io::Result<()> {
-	let listener = TcpListener::bind(format!("127.0.0.1:{}", port))?;
+/// Listens on `remote` in the repl, and connects everything it accepts to
+/// `target`, a `host:port` on this side.
+pub async fn start_reverse(settings: ConnectionSettings, remote: u16, target: String) {
+	if let Err(err) = run_reverse(settings, remote, target).await {
+		error!("{}", err);
+		exit(exit_status(&err));
+	}
+}
+
+async fn run(settings: ConnectionSettings, connection: Connection, local: u16) -> io::Result<()> {
+	let listener = TcpListener::bind(("127.0.0.1", local)).await?;
+	let session = connect(settings).await?;
 
-	for stream in listener.incoming() {
-		if let Ok(mut stream) = stream {
-			let connection = connection.clone();
-			thread::spawn(move || {
-				if let Err(error) = handle_client(connection, &mut stream) {
-					warn!("failed to handle incoming stream");
+	loop {
+		if let Ok((mut stream, _)) = listener.accept().await {
+			let (tx, rx) = session.open(connection.clone())?;
+			tokio::spawn(async move {
+				if let Err(error) = handle_client(tx, rx, &mut stream).await {
+					warn!("failed to handle incoming stream: {}", error);
 
-					let _ = stream.write_fmt(format_args!("atb error {}", error));
-					let _ = stream.shutdown(Shutdown::Both);
+					let _ = stream.write_all(format!("atb error {}", error).as_bytes()).await;
+					let _ = stream.shutdown().await;
 				}
 			});
 		} else {
 			warn!("incoming stream failed to connect");
 		}
 	}
-
-	Ok(())
 }
 
-fn handle_client(
-	connection: ConnectionSettings,
+/// Relays between a channel and a local stream, until both have stopped
+/// writing. Either one stopping is passed on to the other as a half-close, so
+/// that the answer to whatever was sent before it still arrives.
+pub async fn handle_client(
+	tx: Channel,
+	mut rx: UnboundedReceiver<Output>,
 	stream: &mut TcpStream,
 ) -> io::Result<()> {
-	let (tx, rx) = connect(connection)?;
-
-	stream.set_nonblocking(true)?;
+	let mut buffer = vec![ 0; BUFFER_SIZE ];
+	let mut reading = true;
+	let mut writing = true;
 
 	loop {
-		let mut buffer = [ 0; 256 ];
-		match stream.read(&mut buffer) {
-			Ok(0) => {
-				let _ = tx.send(Input::End);
-				break
+		tokio::select! {
+			read = stream.read(&mut buffer), if reading => match read? {
+				0 => {
+					let _ = tx.send(Input::Eof);
+					reading = false;
+				},
+				read => {
+					let _ = tx.send(Input::Data(buffer[..read].to_vec()));
+				},
 			},
-			Ok(read) => {
-				let _ = tx.send(Input::Data(buffer[..read].to_vec()));
+			output = rx.recv() => match output {
+				Some(Output::Data(data)) => stream.write_all(data.as_slice()).await?,
+				Some(Output::Error(error)) => return Err(error.into()),
+				Some(Output::Eof) => {
+					stream.shutdown().await?;
+					writing = false;
+				},
+				Some(Output::Closed) => break,
+				Some(_) => (),
+				None => return Err(io::ErrorKind::Other.into()),
 			},
-			Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
-			Err(error) => return Err(error),
 		}
+	}
 
-		match rx.try_recv() {
-			Ok(output) => match output {
-				Output::Data(data) => {
-					let _ = stream.write(data.as_slice());
-				},
-				Output::Closed => break,
-				_ => (),
+	if writing {
+		stream.shutdown().await?;
+	}
+
+	Ok(())
+}
+
+async fn run_reverse(settings: ConnectionSettings, remote: u16, target: String) -> io::Result<()> {
+	let session = connect(settings).await?;
+	let (_listener, mut rx) = session.open(Connection::ReversePort(remote))?;
+
+	loop {
+		match rx.recv().await {
+			Some(Output::Opened) => info!("forwarding port {} in the repl to {}", remote, target),
+			Some(Output::Accepted(tx, rx)) => {
+				let target = target.clone();
+				tokio::spawn(async move {
+					let mut stream = match TcpStream::connect(target.as_str()).await {
+						Ok(stream) => stream,
+						Err(error) => {
+							warn!("failed to connect to {}: {}", target, error);
+							let _ = tx.send(Input::End);
+							return
+						},
+					};
+
+					if let Err(error) = handle_client(tx, rx, &mut stream).await {
+						warn!("failed to handle forwarded stream: {}", error);
+					}
+				});
 			},
-			Err(TryRecvError::Empty) => (),
-			Err(_) => return Err(ErrorKind::Other.into()),
+			Some(Output::Error(error)) => return Err(error.into()),
+			Some(Output::Closed) | None => return Err(io::ErrorKind::ConnectionAborted.into()),
+			Some(_) => (),
 		}
 	}
+}
+
+/// Forwards datagrams from the local UDP port, giving each peer that sends to
+/// it a channel of its own so that replies find their way back.
+async fn run_udp(settings: ConnectionSettings, connection: Connection, local: u16) -> io::Result<()> {
+	let socket = Arc::new(UdpSocket::bind(("127.0.0.1", local)).await?);
+	let session = connect(settings).await?;
+
+	let mut peers: HashMap<SocketAddr, UnboundedSender<Vec<u8>>> = HashMap::new();
+	let mut buffer = vec![ 0; DATAGRAM_SIZE ];
+
+	loop {
+		let (received, peer) = match socket.recv_from(&mut buffer).await {
+			Ok(received) => received,
+			Err(error) => {
+				warn!("failed to receive datagram: {}", error);
+				continue
+			},
+		};
+
+		let datagram = buffer[..received].to_vec();
+		// a peer whose session ended gets a new one
+		let datagram = match peers.get(&peer) {
+			Some(datagram_tx) => match datagram_tx.send(datagram) {
+				Ok(()) => continue,
+				Err(mpsc::error::SendError(datagram)) => datagram,
+			},
+			None => datagram,
+		};
+
+		peers.retain(|_, datagram_tx| !datagram_tx.is_closed());
+
+		let (tx, rx) = session.open(connection.clone())?;
+		let (datagram_tx, datagram_rx) = mpsc::unbounded_channel();
+		let _ = datagram_tx.send(datagram);
+		peers.insert(peer, datagram_tx);
+
+		debug!("opened UDP session for {}", peer);
+		tokio::spawn(handle_peer(tx, rx, datagram_rx, socket.clone(), peer));
+	}
+}
 
-	stream.shutdown(Shutdown::Both)?;
+async fn handle_peer(
+	tx: Channel,
+	mut rx: UnboundedReceiver<Output>,
+	mut datagrams: UnboundedReceiver<Vec<u8>>,
+	socket: Arc<UdpSocket>,
+	peer: SocketAddr,
+) {
+	let idle = time::sleep(UDP_IDLE_TIMEOUT);
+	tokio::pin!(idle);
 
-	Ok(())
+	loop {
+		tokio::select! {
+			Some(datagram) = datagrams.recv() => {
+				idle.as_mut().reset(Instant::now() + UDP_IDLE_TIMEOUT);
+
+				if tx.send(Input::Data(datagram)).is_err() {
+					break
+				}
+			},
+			output = rx.recv() => match output {
+				Some(Output::Data(data)) => {
+					idle.as_mut().reset(Instant::now() + UDP_IDLE_TIMEOUT);
+
+					if let Err(error) = socket.send_to(data.as_slice(), peer).await {
+						debug!("failed to send datagram to {}: {}", peer, error);
+					}
+				},
+				Some(Output::Error(error)) => {
+					warn!("failed to forward datagrams from {}: {}", peer, error);
+					break
+				},
+				Some(Output::Closed) | None => break,
+				Some(_) => (),
+			},
+			_ = &mut idle => {
+				debug!("closing idle UDP session for {}", peer);
+				let _ = tx.send(Input::End);
+				break
+			},
+		}
+	}
 }
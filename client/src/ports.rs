@@ -0,0 +1,72 @@
+use crate::table::print_table;
+use crate::websocket::{ connect, exit_status, ConnectionSettings, Listener };
+
+use std::io;
+use std::process::exit;
+
+use serde_derive::Serialize;
+
+pub async fn start(settings: ConnectionSettings, json: bool) {
+	if let Err(err) = run(settings, json).await {
+		error!("{}", err);
+		exit(exit_status(&err));
+	}
+}
+
+async fn run(settings: ConnectionSettings, json: bool) -> io::Result<()> {
+	let session = connect(settings).await?;
+	let listeners = session.listeners().await;
+	session.end().await;
+
+	let mut rows: Vec<Row> = listeners?.iter().map(Row::from).collect();
+	rows.sort_by(|a, b| (a.port, &a.protocol, &a.address).cmp(&(b.port, &b.protocol, &b.address)));
+
+	if json {
+		let output = serde_json::to_string_pretty(&rows)
+			.map_err(io::Error::other)?;
+		println!("{}", output);
+	} else {
+		print_listeners(&rows);
+	}
+
+	Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct Row {
+	protocol: String,
+	address: String,
+	port: u16,
+	pid: Option<u32>,
+	process: Option<String>,
+	uid: u32,
+}
+
+impl From<&Listener> for Row {
+	fn from(listener: &Listener) -> Self {
+		Self {
+			protocol: listener.transport.to_string(),
+			address: listener.address.to_string(),
+			port: listener.port,
+			pid: listener.pid,
+			process: listener.process.clone(),
+			uid: listener.uid,
+		}
+	}
+}
+
+fn print_listeners(rows: &[Row]) {
+	let cells: Vec<[String; 6]> = rows.iter()
+		.map(|row| [
+			row.protocol.clone(),
+			row.address.clone(),
+			row.port.to_string(),
+			row.pid.map_or_else(|| "-".into(), |pid| pid.to_string()),
+			row.process.clone().unwrap_or_else(|| "-".into()),
+			row.uid.to_string(),
+		])
+		.collect();
+
+	let table: Vec<&[String]> = cells.iter().map(|row| &row[..]).collect();
+	print_table(&[ "PROTO", "ADDRESS", "PORT", "PID", "PROCESS", "UID" ], &table);
+}
@@ -0,0 +1,83 @@
+use crate::table::print_table;
+use crate::websocket::{ connect, exit_status, ConnectionSettings, ShellSession };
+
+use std::io;
+use std::process::exit;
+use std::time::SystemTime;
+
+use serde_derive::Serialize;
+
+pub async fn start(settings: ConnectionSettings, json: bool) {
+	if let Err(err) = run(settings, json).await {
+		error!("{}", err);
+		exit(exit_status(&err));
+	}
+}
+
+async fn run(settings: ConnectionSettings, json: bool) -> io::Result<()> {
+	let session = connect(settings).await?;
+	let sessions = session.sessions().await;
+	session.end().await;
+
+	let rows: Vec<Row> = sessions?.iter().map(Row::from).collect();
+
+	if json {
+		let output = serde_json::to_string_pretty(&rows)
+			.map_err(io::Error::other)?;
+		println!("{}", output);
+	} else {
+		print_sessions(&rows);
+	}
+
+	Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct Row {
+	id: u32,
+	pid: u32,
+	command: String,
+	attached: bool,
+	started: u64,
+}
+
+impl From<&ShellSession> for Row {
+	fn from(session: &ShellSession) -> Self {
+		Self {
+			id: session.id,
+			pid: session.pid,
+			command: session.command.clone(),
+			attached: session.attached,
+			started: session.started,
+		}
+	}
+}
+
+fn print_sessions(rows: &[Row]) {
+	let now = SystemTime::now()
+		.duration_since(SystemTime::UNIX_EPOCH)
+		.map_or(0, |since| since.as_secs());
+
+	let cells: Vec<[String; 5]> = rows.iter()
+		.map(|row| [
+			row.id.to_string(),
+			row.pid.to_string(),
+			age(now.saturating_sub(row.started)),
+			if row.attached { "attached" } else { "detached" }.into(),
+			row.command.clone(),
+		])
+		.collect();
+
+	let table: Vec<&[String]> = cells.iter().map(|row| &row[..]).collect();
+	print_table(&[ "ID", "PID", "AGE", "STATE", "COMMAND" ], &table);
+}
+
+// in the largest unit that fits, like `ps`
+fn age(seconds: u64) -> String {
+	match seconds {
+		0..=59 => format!("{}s", seconds),
+		60..=3599 => format!("{}m", seconds / 60),
+		3600..=86399 => format!("{}h", seconds / 3600),
+		_ => format!("{}d", seconds / 86400),
+	}
+}
@@ -1,17 +1,20 @@
-use crate::websocket::{ connect, ConnectionSettings, Input, Output };
+use crate::websocket::{ connect, exit_status, Connection, ConnectionSettings, Input, Output };
 
 use std::thread;
 use std::io::{ self, ErrorKind, Read, Write };
 use std::process::exit;
-use std::sync::mpsc::{ self, TryRecvError };
 use std::time::Duration;
 
+use tokio::sync::mpsc;
+use tokio::time;
+
 use vt100::Parser;
 
 const MENU_PREFIX: &str = "\x1b[107;34m Autobahn shell\x1b[30m |";
 const MENU_PROMPT: &str = " \x1b[32m^Z for menu \x1b[0m";
-const MENU_CMD: &str = " \x1b[32;1m(q)\x1b[22muit, \x1b[1m(Esc)\x1b[22m cancel \x1b[0m";
+const MENU_CMD: &str = " \x1b[32;1m(q)\x1b[22muit, \x1b[1m(d)\x1b[22metach, \x1b[1m(Esc)\x1b[22m cancel \x1b[0m";
 const MENU_ERROR: &str = " \x1b[31mUnknown command \x1b[0m";
+const MENU_RECONNECTING: &str = " \x1b[33mConnection lost, reconnecting... \x1b[0m";
 const MENU_CHAR: u8 = 26; // Ctrl+Z
 const CLEAR_SCREEN: &str = "\r\x1b[2J\r\x1b[H";
 const CLEAR_ROW: &str = "\x1b[2K";
@@ -19,36 +22,48 @@ const RESET_CURSOR: &str = "\x1b[H";
 const MOVE_CURSOR: &str = "\x1b[%y;%xH";
 const END_CURSOR: &str = "\x1b[0m\x1b[?25h";
 
-pub fn start(connection: ConnectionSettings) {
-	if let Err(err) = run(connection) {
-		let _ = unsafe { crate::console::disable_raw_mode() };
-
-		error!("{}", err);
-		exit(1);
+/// Runs a shell in the repl, or attaches to one that is running, and exits
+/// with its status once it finishes.
+pub async fn start(settings: ConnectionSettings, connection: Connection) {
+	match run(settings, connection).await {
+		Ok(status) => exit(status),
+		Err(err) => {
+			let _ = unsafe { crate::console::disable_raw_mode() };
+
+			error!("{}", err);
+			exit(exit_status(&err));
+		},
 	}
 }
 
-fn run(connection: ConnectionSettings) -> io::Result<()> {
-	let (tx, rx) = connect(connection)?;
+async fn run(settings: ConnectionSettings, connection: Connection) -> io::Result<i32> {
+	let session = connect(settings).await?;
+	let (tx, mut rx) = session.open(connection)?;
 
 	print!("{}", CLEAR_SCREEN);
 	let _ = io::stdout().flush();
 
 	unsafe { crate::console::enable_raw_mode() }?;
 
-	let (input_tx, input_rx) = mpsc::channel();
+	// reading the terminal blocks, so it gets a thread of its own
+	let (input_tx, mut input_rx) = mpsc::unbounded_channel();
 
 	thread::spawn(move || {
 		let mut stdin = io::stdin();
 		let mut buffer = [ 0; 256 ];
 
 		loop {
-			if let Ok(read) = stdin.read(&mut buffer) {
-				let _ = input_tx.send(buffer[..read].to_vec());
+			match stdin.read(&mut buffer) {
+				Ok(0) | Err(_) => break,
+				Ok(read) => if input_tx.send(buffer[..read].to_vec()).is_err() {
+					break
+				},
 			}
 		}
 	});
 
+	let mut resizes = crate::console::Resizes::new()?;
+
 	let (mut cols, mut rows) = unsafe { crate::console::term_size() }?;
 	let mut parser = Parser::new(rows - 1, cols, 0);
 	let _ = tx.send(Input::Winch(cols, rows));
@@ -56,106 +71,131 @@ fn run(connection: ConnectionSettings) -> io::Result<()> {
 	let _ = show_menu((cols, rows), MENU_PROMPT);
 
 	let mut stdout = io::stdout();
-	let mut exit = 0;
+	let mut status = None;
+	let mut session_id = None;
+	let mut detached = false;
 
 	loop {
-		let (new_cols, new_rows) = unsafe { crate::console::term_size() }?;
-		if new_cols != cols || new_rows != rows {
-			cols = new_cols;
-			rows = new_rows;
+		tokio::select! {
+			_ = resizes.recv() => {
+				let (new_cols, new_rows) = unsafe { crate::console::term_size() }?;
+				if new_cols != cols || new_rows != rows {
+					cols = new_cols;
+					rows = new_rows;
 
-			let _ = tx.send(Input::Winch(cols, rows));
+					let _ = tx.send(Input::Winch(cols, rows));
 
-			parser.set_size(rows - 1, cols);
-		}
-
-		match rx.try_recv() {
-			Ok(Output::Data(data)) => {
-				parser.process(data.as_slice());
-				
-				let contents = parser.screen().contents_formatted();
-				let _ = stdout.write(RESET_CURSOR.as_bytes());
-				let _ = stdout.write(contents.as_slice());
-				let _ = stdout.flush();
-
-				let _ = show_menu((cols, rows), MENU_PROMPT);
-
-				let pos = parser.screen().cursor_position();
-				let _ = stdout.write(
-					MOVE_CURSOR
-						.replace("%x", &(pos.1 + 1).to_string())
-						.replace("%y", &(pos.0 + 1).to_string())
-						.as_bytes()
-				);
-				let _ = stdout.flush();
-			},
-			Ok(Output::Died(code)) => {
-				exit = code;
-				break
+					parser.set_size(rows - 1, cols);
+				}
 			},
-			Err(TryRecvError::Empty) => (),
-			_ => return Err(ErrorKind::Other.into()),
-		}
+			output = rx.recv() => match output {
+				Some(Output::Data(data)) => {
+					parser.process(data.as_slice());
 
-		match input_rx.try_recv() {
-			Ok(data) => {
-				if let Some(p) = data.iter().position(|byte| *byte == MENU_CHAR) {
-					if p > 0 {
-						let _ = tx.send(Input::Data(data[..p].to_vec()));
-					}
-					let _ = tx.send(Input::Stop);
-
-					let _ = show_menu((cols, rows), MENU_CMD);
-
-					if let Ok(input) = input_rx.recv() {
-						match input[0] as char {
-							'\x1b' | 'x' => (),
-							'q' => {
-								let _ = tx.send(Input::End);
-								break
-							},
-							_ => {
-								let _ = show_menu((cols, rows), MENU_ERROR);
-								thread::sleep(Duration::from_millis(2000));
-							},
-						}
-					}
+					let contents = parser.screen().contents_formatted();
+					let _ = stdout.write(RESET_CURSOR.as_bytes());
+					let _ = stdout.write(contents.as_slice());
+					let _ = stdout.flush();
 
 					let _ = show_menu((cols, rows), MENU_PROMPT);
+					let _ = restore_cursor(&parser);
+				},
+				Some(Output::Died(died)) => {
+					status = Some(died);
+					break
+				},
+				Some(Output::Error(error)) => return Err(error.into()),
+				Some(Output::Closed) => return Err(io::Error::other("shell closed by server")),
+				Some(Output::Session(id)) => session_id = Some(id),
+				Some(Output::Reconnecting) => {
+					let _ = show_menu((cols, rows), MENU_RECONNECTING);
+					let _ = restore_cursor(&parser);
+				},
+				Some(Output::Reconnected) => {
+					let _ = show_menu((cols, rows), MENU_PROMPT);
+					let _ = restore_cursor(&parser);
+				},
+				Some(Output::Opened) | Some(Output::ErrorData(_)) | Some(Output::Eof) | Some(Output::Accepted(_, _)) => (),
+				None => return Err(ErrorKind::Other.into()),
+			},
+			input = input_rx.recv() => match input {
+				Some(data) => {
+					if let Some(p) = data.iter().position(|byte| *byte == MENU_CHAR) {
+						if p > 0 {
+							let _ = tx.send(Input::Data(data[..p].to_vec()));
+						}
+						let _ = tx.send(Input::Stop);
+
+						let _ = show_menu((cols, rows), MENU_CMD);
+
+						if let Some(input) = input_rx.recv().await {
+							match input[0] as char {
+								'\x1b' | 'x' => (),
+								'q' => {
+									let _ = tx.send(Input::End);
+									break
+								},
+								// the shell carries on without us, so it mustn't stay stopped
+								'd' if session_id.is_some() => {
+									let _ = tx.send(Input::Continue);
+									detached = true;
+									break
+								},
+								_ => {
+									let _ = show_menu((cols, rows), MENU_ERROR);
+									time::sleep(Duration::from_millis(2000)).await;
+								},
+							}
+						}
 
-					let pos = parser.screen().cursor_position();
-					let _ = stdout.write(
-						MOVE_CURSOR
-							.replace("%x", &(pos.1 + 1).to_string())
-							.replace("%y", &(pos.0 + 1).to_string())
-							.as_bytes()
-					);
-					let _ = stdout.flush();
+						let _ = show_menu((cols, rows), MENU_PROMPT);
+						let _ = restore_cursor(&parser);
 
-					let _ = tx.send(Input::Continue);
-					if p < (data.len() - 1) {
-						let _ = tx.send(Input::Data(data[(p+1)..].to_vec()));
+						let _ = tx.send(Input::Continue);
+						if p < (data.len() - 1) {
+							let _ = tx.send(Input::Data(data[(p+1)..].to_vec()));
+						}
+					} else {
+						let _ = tx.send(Input::Data(data));
 					}
-				} else {
-					let _ = tx.send(Input::Data(data));
-				}
+				},
+				None => return Err(ErrorKind::Other.into()),
 			},
-			Err(TryRecvError::Empty) => (),
-			_ => return Err(ErrorKind::Other.into()),
 		}
 	}
 
+	session.end().await;
+
 	unsafe { crate::console::disable_raw_mode() }?;
 
 	print!("{}{}", CLEAR_SCREEN, END_CURSOR);
 
-	if exit == 255 {
-		println!("Process died unusually");
-	} else {
-		println!("Process exited with code {}", exit);
+	match (status, session_id) {
+		(Some(status), _) => {
+			println!("Process {}", status);
+			Ok(status.code())
+		},
+		(None, Some(id)) if detached => {
+			println!("Detached from session {}, attach again with `shell --attach {}`", id, id);
+			Ok(0)
+		},
+		// nothing to report when the shell was quit from the menu
+		(None, _) => Ok(0),
 	}
+}
 
-	Ok(())
+// puts the cursor back where the shell left it, after drawing elsewhere
+fn restore_cursor(parser: &Parser) -> io::Result<()> {
+	let pos = parser.screen().cursor_position();
+
+	let mut stdout = io::stdout();
+	stdout.write_all(
+		MOVE_CURSOR
+			.replace("%x", &(pos.1 + 1).to_string())
+			.replace("%y", &(pos.0 + 1).to_string())
+			.as_bytes()
+	)?;
+	stdout.flush()
 }
 
 fn show_menu(dim: (u16, u16), message: &str) -> io::Result<()> {
@@ -164,10 +204,10 @@ fn show_menu(dim: (u16, u16), message: &str) -> io::Result<()> {
 		.replace("%y", &dim.1.to_string());
 
 	let mut stdout = io::stdout();
-	stdout.write(mvcs.as_bytes())?;
-	stdout.write(CLEAR_ROW.as_bytes())?;
-	stdout.write(MENU_PREFIX.as_bytes())?;
-	stdout.write(message.as_bytes())?;
+	stdout.write_all(mvcs.as_bytes())?;
+	stdout.write_all(CLEAR_ROW.as_bytes())?;
+	stdout.write_all(MENU_PREFIX.as_bytes())?;
+	stdout.write_all(message.as_bytes())?;
 	stdout.flush()?;
 	
 	Ok(())
@@ -0,0 +1,170 @@
+use crate::portfwd;
+use crate::websocket::{ connect, exit_status, Connection, ConnectionSettings, ErrorCode, Output, Repl, Session };
+
+use std::io::{ self, Error, ErrorKind };
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+use std::process::exit;
+use std::sync::Arc;
+
+use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+use tokio::net::{ TcpListener, TcpStream };
+
+const DEFAULT_PORT: u16 = 1080;
+
+// RFC 1928
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0x00;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const SUCCEEDED: u8 = 0x00;
+const GENERAL_FAILURE: u8 = 0x01;
+const NOT_ALLOWED: u8 = 0x02;
+const CONNECTION_REFUSED: u8 = 0x05;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+pub async fn start(settings: ConnectionSettings, local: Option<u16>) {
+	if let Err(err) = run(settings, local.unwrap_or(DEFAULT_PORT)).await {
+		error!("{}", err);
+		exit(exit_status(&err));
+	}
+}
+
+async fn run(settings: ConnectionSettings, local: u16) -> io::Result<()> {
+	let listener = TcpListener::bind(("127.0.0.1", local)).await?;
+	let repl = settings.repl.clone();
+	let session = Arc::new(connect(settings).await?);
+
+	info!("SOCKS proxy for {} listening on port {}", repl.domain(), local);
+
+	loop {
+		if let Ok((stream, _)) = listener.accept().await {
+			let session = session.clone();
+			let repl = repl.clone();
+
+			tokio::spawn(async move {
+				if let Err(error) = handle_client(&session, &repl, stream).await {
+					warn!("failed to handle SOCKS client: {}", error);
+				}
+			});
+		} else {
+			warn!("incoming stream failed to connect");
+		}
+	}
+}
+
+async fn handle_client(session: &Session, repl: &Repl, mut stream: TcpStream) -> io::Result<()> {
+	negotiate(&mut stream).await?;
+
+	let (command, host, port) = match read_request(&mut stream).await? {
+		Ok(request) => request,
+		Err(reply) => return send_reply(&mut stream, reply).await,
+	};
+
+	if command != CONNECT {
+		debug!("unsupported SOCKS command {}", command);
+		return send_reply(&mut stream, COMMAND_NOT_SUPPORTED).await
+	}
+
+	// the server decides whether hosts outside the repl are allowed
+	let connection = if is_repl(&host, repl) {
+		Connection::Port(port)
+	} else {
+		Connection::Remote(host.clone(), port)
+	};
+	let (tx, mut rx) = session.open(connection)?;
+
+	// only reply once the server knows whether it could connect
+	match rx.recv().await {
+		Some(Output::Opened) => send_reply(&mut stream, SUCCEEDED).await?,
+		Some(Output::Error(error)) => {
+			debug!("failed to connect to {}:{}: {}", host, port, error);
+
+			return send_reply(&mut stream, match error.code {
+				ErrorCode::PermissionDenied => NOT_ALLOWED,
+				ErrorCode::ConnectionRefused => CONNECTION_REFUSED,
+				_ => GENERAL_FAILURE,
+			}).await
+		},
+		_ => return send_reply(&mut stream, GENERAL_FAILURE).await,
+	}
+
+	debug!("connected to {}:{}", host, port);
+	portfwd::handle_client(tx, rx, &mut stream).await
+}
+
+// agrees on no authentication, which is all we offer as we only listen locally
+async fn negotiate(stream: &mut TcpStream) -> io::Result<()> {
+	let mut header = [ 0; 2 ];
+	stream.read_exact(&mut header).await?;
+	if header[0] != VERSION {
+		return Err(Error::new(ErrorKind::InvalidData, "not a SOCKS5 client"))
+	}
+
+	let mut methods = vec![ 0; header[1] as usize ];
+	stream.read_exact(&mut methods).await?;
+
+	if methods.contains(&NO_AUTHENTICATION) {
+		stream.write_all(&[ VERSION, NO_AUTHENTICATION ]).await
+	} else {
+		stream.write_all(&[ VERSION, NO_ACCEPTABLE_METHODS ]).await?;
+		Err(Error::new(ErrorKind::PermissionDenied, "SOCKS client needs authentication"))
+	}
+}
+
+// the command, host and port, or the reply to refuse with
+async fn read_request(stream: &mut TcpStream) -> io::Result<Result<(u8, String, u16), u8>> {
+	let mut header = [ 0; 4 ];
+	stream.read_exact(&mut header).await?;
+	if header[0] != VERSION {
+		return Err(Error::new(ErrorKind::InvalidData, "not a SOCKS5 request"))
+	}
+
+	let host = match header[3] {
+		ATYP_IPV4 => {
+			let mut octets = [ 0; 4 ];
+			stream.read_exact(&mut octets).await?;
+			Ipv4Addr::from(octets).to_string()
+		},
+		ATYP_IPV6 => {
+			let mut octets = [ 0; 16 ];
+			stream.read_exact(&mut octets).await?;
+			Ipv6Addr::from(octets).to_string()
+		},
+		ATYP_DOMAIN => {
+			let mut name = vec![ 0; stream.read_u8().await? as usize ];
+			stream.read_exact(&mut name).await?;
+			String::from_utf8_lossy(&name).into_owned()
+		},
+		_ => return Ok(Err(ADDRESS_NOT_SUPPORTED)),
+	};
+	let port = stream.read_u16().await?;
+
+	Ok(Ok((header[1], host, port)))
+}
+
+async fn send_reply(stream: &mut TcpStream, reply: u8) -> io::Result<()> {
+	// nothing is bound on this side, so the address is left empty
+	stream.write_all(&[ VERSION, reply, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0 ]).await?;
+
+	if reply != SUCCEEDED {
+		stream.shutdown().await?;
+	}
+
+	Ok(())
+}
+
+// names for the repl itself, which only need the port to be permitted
+fn is_repl(host: &str, repl: &Repl) -> bool {
+	match host.parse::<IpAddr>() {
+		Ok(ip) => ip.is_loopback() || ip.is_unspecified(),
+		Err(_) => {
+			let host = host.trim_end_matches('.');
+			host.eq_ignore_ascii_case("localhost") || host.eq_ignore_ascii_case(&repl.domain())
+		},
+	}
+}
@@ -0,0 +1,25 @@
+/// Prints `rows` under `header` in columns as wide as their widest cell, like
+/// `ps` does.
+pub fn print_table(header: &[&str], rows: &[&[String]]) {
+	let mut widths: Vec<usize> = header.iter().map(|cell| cell.len()).collect();
+	for row in rows {
+		for (width, cell) in widths.iter_mut().zip(row.iter()) {
+			*width = (*width).max(cell.len());
+		}
+	}
+
+	print_row(header, &widths);
+	for row in rows {
+		print_row(row, &widths);
+	}
+}
+
+fn print_row(row: &[impl AsRef<str>], widths: &[usize]) {
+	let line = row.iter()
+		.zip(widths)
+		.map(|(cell, width)| format!("{:<width$}", cell.as_ref(), width = width))
+		.collect::<Vec<_>>()
+		.join("  ");
+
+	println!("{}", line.trim_end());
+}
@@ -1,131 +1,489 @@
-mod message;
+pub use autobahn_protocol::{ Connection, ErrorCode, ExitStatus, Listener, SessionId, ShellSession };
 
-pub use message::{ Connection, Message };
+use autobahn_protocol::{
+	auth, ChannelId, Credential, Handshake, Message, Replay, RequestId, State, PROTOCOL, VERSION,
+};
+use autobahn_protocol::keys::SigningKey;
 
-use std::thread;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
 use std::io::{ self, Error, ErrorKind };
 use std::str::FromStr;
-use std::sync::mpsc::{ self, Receiver, Sender };
+use std::sync::atomic::{ AtomicU32, Ordering };
+use std::time::Duration;
 
-use websocket::{ ClientBuilder, OwnedMessage };
+use futures_util::{ SinkExt, StreamExt };
 
-const PROTOCOL: &str = "autobahn-websocket-tunnel";
-const VERSION: (u8, u8) = (0, 2);
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{ self, UnboundedReceiver, UnboundedSender, WeakUnboundedSender };
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{ self, Instant };
 
-// I hate this
-pub fn connect(
-	options: ConnectionSettings,
-) -> io::Result<(Sender<Input>, Receiver<Output>)> {
+use tokio_tungstenite::{ MaybeTlsStream, WebSocketStream };
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+type ListenersReply = oneshot::Sender<Result<Vec<Listener>, RemoteError>>;
+type SessionsReply = oneshot::Sender<Result<Vec<ShellSession>, RemoteError>>;
+
+/// How often the server is pinged, so that a dead connection is noticed.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+/// How long the server can stay silent before the connection counts as lost.
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long to wait before the first attempt to reconnect. It doubles with
+/// every attempt after that, up to `RECONNECT_MAX_DELAY`.
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+const RECONNECT_ATTEMPTS: u32 = 10;
+/// How long one attempt to reconnect and resume can take.
+const RESUME_TIMEOUT: Duration = Duration::from_secs(20);
+
+pub async fn connect(options: ConnectionSettings) -> io::Result<Session> {
+	let (client, token) = open(&options).await?;
+
+	let (command_tx, command_rx) = mpsc::unbounded_channel();
+	let task = tokio::spawn(run(options, client, token, command_tx.downgrade(), command_rx));
+
+	Ok(Session {
+		commands: command_tx,
+		next_channel: AtomicU32::new(1),
+		task,
+	})
+}
+
+// connects and authenticates, returning the token to resume the session with
+async fn open(options: &ConnectionSettings) -> io::Result<(Client, Vec<u8>)> {
 	let url = format!("wss://{}/__atbws", options.repl.domain());
-	let mut client = ClientBuilder::new(url.as_str())
-		.map_err(|_| {
-			error!("invalid URL {}", url);
-			Error::from(ErrorKind::Other)
-		})?
-		.add_protocol(PROTOCOL)
-		.connect_secure(None)
-		.map_err(|_| {
-			error!("failed to connect websocket");
-			Error::from(ErrorKind::Other)
-		})?;
-
-	let (input_tx, input_rx) = mpsc::channel();
-	let (output_tx, output_rx) = mpsc::channel();
-
-	thread::spawn(move || {
-		if client.send_message(
-			&OwnedMessage::Binary(
-				minicbor::to_vec(
-					Message::Hello(VERSION.0, VERSION.1)
-				).unwrap()
-			)
-		).is_err() { return }
-
-		if client.send_message(
-			&OwnedMessage::Binary(
-				minicbor::to_vec(
-					Message::Authenticate(options.key)
-				).unwrap()
-			)
-		).is_err() { return }
-
-		if let Ok(OwnedMessage::Binary(data)) = client.recv_message() {
-			if let Ok(message) = minicbor::decode(data.as_slice()) {
-				if Message::Authentication(true) != message {
-					return
-				}
-			}
-		} else { return }
-
-		if client.send_message(
-			&OwnedMessage::Binary(
-				minicbor::to_vec(
-					Message::ConnectionType(options.connection)
-				).unwrap()
-			)
-		).is_err() { return }
-	
-		if client.set_nonblocking(true).is_err() { return }
-
-		loop {
-			if let Ok(message) = client.recv_message() {
-				match message {
-					OwnedMessage::Close(_) => break,
-					OwnedMessage::Ping(data) => {
-						debug!("websocket pinged");
-						let _ = client.send_message(&OwnedMessage::Pong(data));
-					},
-					OwnedMessage::Binary(data) => {
-						if let Ok(message) = minicbor::decode(data.as_slice()) {
-							match message {
-								Message::ChildDeath(exit) => {
-									let _ = output_tx.send(Output::Died(exit));
-								},
-								Message::Error => break,
-								Message::SocketClose => {
-									let _ = output_tx.send(Output::Closed);
-								},
-								Message::SocketOutput(data) | Message::TerminalOutput(data) => {
-									let _ = output_tx.send(Output::Data(data));
-								},
-								_ => (),
+	let mut request = url.as_str()
+		.into_client_request()
+		.map_err(|_| Error::other(format!("invalid URL {}", url)))?;
+	request.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(PROTOCOL));
+
+	// failing is only worth shouting about once the caller gives up
+	let (mut client, _) = tokio_tungstenite::connect_async(request).await
+		.map_err(|error| Error::other(format!("failed to connect websocket: {}", error)))?;
+
+	let mut handshake = Handshake::new();
+
+	let hello = Message::Hello(VERSION.0, VERSION.1);
+	send(&mut client, &hello).await?;
+	let _ = handshake.observe(&hello);
+
+	let nonce = match recv(&mut client).await? {
+		Some(Message::Challenge(nonce)) => nonce,
+		Some(Message::Error(code, message)) => return Err(RemoteError { code, message }.into()),
+		_ => return Err(ErrorKind::ConnectionAborted.into()),
+	};
+
+	let _ = handshake.observe(&Message::Challenge(nonce.clone()));
+
+	let authenticate = Message::Authenticate(match options.credentials {
+		Credentials::Key(ref key) => Credential::Key(auth::respond(key.as_bytes(), &nonce)),
+		Credentials::Identity(ref key) => Credential::PublicKey(
+			key.verifying_key().to_bytes().to_vec(),
+			auth::sign(key, &nonce),
+		),
+	});
+	send(&mut client, &authenticate).await?;
+	let _ = handshake.observe(&authenticate);
+
+	match recv(&mut client).await? {
+		Some(message) if handshake.observe(&message) == Ok(State::Established) => (),
+		Some(Message::Error(code, message)) => return Err(RemoteError { code, message }.into()),
+		_ => return Err(Error::new(ErrorKind::PermissionDenied, "authentication failed")),
+	}
+
+	match recv(&mut client).await? {
+		Some(Message::ResumeToken(token)) => Ok((client, token)),
+		Some(Message::Error(code, message)) => Err(RemoteError { code, message }.into()),
+		_ => Err(ErrorKind::ConnectionAborted.into()),
+	}
+}
+
+// carries on with the session over a new connection, sending again whatever
+// the server missed
+async fn resume(options: &ConnectionSettings, token: &[u8], replay: &mut Replay) -> io::Result<Client> {
+	let (mut client, _) = open(options).await?;
+
+	send(&mut client, &Message::Resume(token.to_vec(), replay.received())).await?;
+
+	let received = match recv(&mut client).await? {
+		Some(Message::Resumed(received)) => received,
+		Some(Message::Error(code, message)) => return Err(RemoteError { code, message }.into()),
+		_ => return Err(ErrorKind::ConnectionAborted.into()),
+	};
+
+	let unacknowledged = replay.resume(received)
+		.map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+	for data in unacknowledged {
+		send_data(&mut client, data.to_vec()).await?;
+	}
+
+	Ok(client)
+}
+
+// keeps trying to resume, backing off exponentially, until the server takes
+// it or refuses
+async fn reconnect(options: &ConnectionSettings, token: &[u8], replay: &mut Replay) -> io::Result<Client> {
+	let mut delay = RECONNECT_DELAY;
+	let mut attempt = 1;
+
+	loop {
+		time::sleep(delay).await;
+
+		let error = match time::timeout(RESUME_TIMEOUT, resume(options, token, replay)).await {
+			Ok(Ok(client)) => return Ok(client),
+			Ok(Err(error)) => error,
+			Err(_) => ErrorKind::TimedOut.into(),
+		};
+
+		// the server answered, and won't change its mind
+		let refused = error.kind() == ErrorKind::PermissionDenied
+			|| error.get_ref().is_some_and(|inner| inner.is::<RemoteError>());
+
+		if refused || attempt == RECONNECT_ATTEMPTS {
+			return Err(error)
+		}
+
+		delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+		attempt += 1;
+
+		warn!("failed to reconnect: {}, trying again in {:?}", error, delay);
+	}
+}
+
+// `command_tx` is only for the channels the server opens, and is weak so that
+// dropping the session still ends the loop
+async fn run(
+	options: ConnectionSettings,
+	mut client: Client,
+	token: Vec<u8>,
+	command_tx: WeakUnboundedSender<Command>,
+	mut commands: UnboundedReceiver<Command>,
+) {
+	let mut channels: HashMap<ChannelId, (Connection, UnboundedSender<Output>)> = HashMap::new();
+	let mut requests: HashMap<RequestId, Reply> = HashMap::new();
+	let mut next_request: RequestId = 1;
+	let mut replay = Replay::new();
+
+	loop {
+		// only returns an error when the connection is lost
+		let result = async {
+			let mut keepalive = time::interval(KEEPALIVE_INTERVAL);
+			let mut last_heard = Instant::now();
+
+			loop {
+				tokio::select! {
+					message = client.next() => {
+						last_heard = Instant::now();
+
+						let data = match message {
+							None | Some(Err(_)) | Some(Ok(WsMessage::Close(_))) =>
+								return Err(Error::from(ErrorKind::ConnectionAborted)),
+							Some(Ok(WsMessage::Binary(data))) => data,
+							Some(Ok(_)) => continue,
+						};
+
+						let message = match minicbor::decode::<Message>(data.as_slice()) {
+							Ok(message) => message,
+							Err(_) => continue,
+						};
+
+						if let Some(ack) = replay.receive(&message) {
+							send(&mut client, &ack).await?;
+						}
+
+						let channel = message.channel().unwrap_or_default();
+						let output = match message {
+							Message::Ack(received) => {
+								if let Err(error) = replay.acknowledge(received) {
+									warn!("server acknowledged what it couldn't have: {}", error);
+								}
+
+								continue
+							},
+							Message::ChannelAccept(_, accepted) => {
+								let listener = channels.get(&channel)
+									.map(|(connection, output_tx)| (connection.clone(), output_tx.clone()));
+
+								match (listener, command_tx.upgrade()) {
+									(Some((Connection::ReversePort(port), listener_tx)), Some(commands)) => {
+										let (output_tx, output_rx) = mpsc::unbounded_channel();
+										channels.insert(accepted, (Connection::Port(port), output_tx));
+
+										let channel = Channel { id: accepted, commands };
+										let _ = listener_tx.send(Output::Accepted(channel, output_rx));
+									},
+									// nobody is left to take the connection
+									_ => send_data(&mut client, replay.record(&Message::ChannelClose(accepted))).await?,
+								}
+
+								continue
+							},
+							Message::ChannelOpened(_) => Output::Opened,
+							Message::ChildDeath(_, exit) => Output::Died(exit),
+							Message::ChannelClose(_) => Output::Closed,
+							Message::ChannelError(_, code, message) =>
+								Output::Error(RemoteError { code, message }),
+							Message::Error(code, message) => {
+								error!("server ended the session: {}", message);
+
+								fail(&mut channels, &mut requests, RemoteError { code, message });
+								return Ok(())
+							},
+							Message::Listeners(request, listeners) => {
+								if let Some(Reply::Listeners(reply)) = requests.remove(&request) {
+									let _ = reply.send(Ok(listeners));
+								}
+
+								continue
+							},
+							Message::Sessions(request, sessions) => {
+								if let Some(Reply::Sessions(reply)) = requests.remove(&request) {
+									let _ = reply.send(Ok(sessions));
+								}
+
+								continue
+							},
+							Message::SessionAttached(_, session) => Output::Session(session),
+							Message::SocketOutput(_, data) | Message::TerminalOutput(_, data) =>
+								Output::Data(data),
+							Message::ErrorOutput(_, data) => Output::ErrorData(data),
+							Message::OutputEnd(_) => Output::Eof,
+							_ => continue,
+						};
+
+						if matches!(output, Output::Closed) {
+							if let Some((_, output_tx)) = channels.remove(&channel) {
+								let _ = output_tx.send(output);
 							}
+						} else if let Some((_, output_tx)) = channels.get(&channel) {
+							let _ = output_tx.send(output);
 						}
 					},
-					_ => (),
+					command = commands.recv() => {
+						let message = match command {
+							Some(Command::Open(channel, connection, output_tx)) => {
+								channels.insert(channel, (connection.clone(), output_tx));
+								Message::ChannelOpen(channel, connection)
+							},
+							Some(Command::Request(reply)) => {
+								let request = next_request;
+								next_request = next_request.wrapping_add(1);
+
+								let message = match reply {
+									Reply::Listeners(_) => Message::ListListeners(request),
+									Reply::Sessions(_) => Message::ListSessions(request),
+								};
+								requests.insert(request, reply);
+								message
+							},
+							Some(Command::Input(channel, input)) => {
+								let connection = match channels.get(&channel) {
+									Some((connection, _)) => connection.clone(),
+									None => continue,
+								};
+
+								match input {
+									Input::Data(data) => match connection {
+										Connection::Shell(_) | Connection::Attach(_) => Message::TerminalInput(channel, data),
+										_ => Message::SocketInput(channel, data),
+									},
+									Input::Continue => Message::SignalContinue(channel),
+									Input::Stop => Message::SignalStop(channel),
+									Input::Winch(w, h) => Message::SignalWinch(channel, w, h),
+									Input::Eof => Message::InputEnd(channel),
+									Input::End => {
+										channels.remove(&channel);
+										Message::ChannelClose(channel)
+									},
+								}
+							},
+							Some(Command::End) | None => {
+								let _ = send_data(&mut client, replay.record(&Message::EndSession)).await;
+								return Ok(())
+							},
+						};
+
+						send_data(&mut client, replay.record(&message)).await?;
+					},
+					_ = keepalive.tick() => {
+						if last_heard.elapsed() > KEEPALIVE_TIMEOUT {
+							return Err(ErrorKind::TimedOut.into())
+						}
+
+						client.send(WsMessage::Ping(Vec::new())).await
+							.map_err(|_| Error::from(ErrorKind::Other))?;
+					},
 				}
 			}
+		}.await;
 
-			if let Ok(input) = input_rx.try_recv() {
-				let _ = client.send_message(
-					&OwnedMessage::Binary(
-						minicbor::to_vec(
-							match input {
-								Input::Data(ref data) => match options.connection {
-									Connection::Shell => Message::TerminalInput(data.clone()),
-									Connection::Port(_) => Message::SocketInput(data.clone()),
-								},
-								Input::Continue => Message::SignalContinue,
-								Input::Stop => Message::SignalStop,
-								Input::Winch(w, h) => Message::SignalWinch(w, h),
-								Input::End => Message::EndSession,
-							}
-						).unwrap()
-					)
-				);
+		let error = match result {
+			Ok(()) => break,
+			Err(error) => error,
+		};
+
+		warn!("lost connection to the server: {}, reconnecting", error);
+		notify_terminals(&channels, || Output::Reconnecting);
+
+		match reconnect(&options, &token, &mut replay).await {
+			Ok(resumed) => {
+				info!("reconnected to the server");
+				notify_terminals(&channels, || Output::Reconnected);
+
+				client = resumed;
+			},
+			Err(error) => {
+				error!("failed to reconnect to the server: {}", error);
 
-				if input == Input::End {
-					break
+				if let Some(remote) = error.get_ref().and_then(|inner| inner.downcast_ref::<RemoteError>()) {
+					fail(&mut channels, &mut requests, remote.clone());
 				}
-			}
+
+				break
+			},
 		}
+	}
 
-		if client.shutdown().is_err() {
-			warn!("failed to shutdown client");
+	if client.close(None).await.is_err() {
+		warn!("failed to shutdown client");
+	}
+}
+
+// tells every channel and request that is waiting that the session is over
+fn fail(
+	channels: &mut HashMap<ChannelId, (Connection, UnboundedSender<Output>)>,
+	requests: &mut HashMap<RequestId, Reply>,
+	error: RemoteError,
+) {
+	for (_, (_, output_tx)) in channels.drain() {
+		let _ = output_tx.send(Output::Error(error.clone()));
+	}
+	for (_, reply) in requests.drain() {
+		match reply {
+			Reply::Listeners(reply) => { let _ = reply.send(Err(error.clone())); },
+			Reply::Sessions(reply) => { let _ = reply.send(Err(error.clone())); },
 		}
-	});
+	}
+}
+
+// someone is watching a terminal, and would otherwise only see it freeze
+fn notify_terminals(
+	channels: &HashMap<ChannelId, (Connection, UnboundedSender<Output>)>,
+	output: impl Fn() -> Output,
+) {
+	for (connection, output_tx) in channels.values() {
+		if let Connection::Shell(_) | Connection::Attach(_) = connection {
+			let _ = output_tx.send(output());
+		}
+	}
+}
+
+async fn send(client: &mut Client, message: &Message) -> io::Result<()> {
+	send_data(client, minicbor::to_vec(message).unwrap()).await
+}
+
+async fn send_data(client: &mut Client, data: Vec<u8>) -> io::Result<()> {
+	client.send(WsMessage::Binary(data)).await
+		.map_err(|_| Error::from(ErrorKind::Other))
+}
+
+async fn recv(client: &mut Client) -> io::Result<Option<Message>> {
+	match client.next().await {
+		Some(Ok(WsMessage::Binary(data))) => Ok(minicbor::decode(data.as_slice()).ok()),
+		Some(Ok(_)) => Ok(None),
+		_ => Err(ErrorKind::ConnectionAborted.into()),
+	}
+}
+
+/// An authenticated connection to the server, over which any number of
+/// channels can be opened.
+pub struct Session {
+	commands: UnboundedSender<Command>,
+	next_channel: AtomicU32,
+	task: JoinHandle<()>,
+}
+
+impl Session {
+	pub fn open(
+		&self,
+		connection: Connection,
+	) -> io::Result<(Channel, UnboundedReceiver<Output>)> {
+		let id = self.next_channel.fetch_add(1, Ordering::Relaxed);
+		let (output_tx, output_rx) = mpsc::unbounded_channel();
+
+		self.commands.send(Command::Open(id, connection, output_tx))
+			.map_err(|_| Error::from(ErrorKind::NotConnected))?;
+
+		Ok((
+			Channel { id, commands: self.commands.clone() },
+			output_rx,
+		))
+	}
+
+	/// Asks the server what is listening for connections.
+	pub async fn listeners(&self) -> io::Result<Vec<Listener>> {
+		let (reply_tx, reply_rx) = oneshot::channel();
+
+		self.commands.send(Command::Request(Reply::Listeners(reply_tx)))
+			.map_err(|_| Error::from(ErrorKind::NotConnected))?;
+
+		match reply_rx.await {
+			Ok(listeners) => Ok(listeners?),
+			Err(_) => Err(ErrorKind::ConnectionAborted.into()),
+		}
+	}
+
+	/// Asks the server which shells are running, to attach to.
+	pub async fn sessions(&self) -> io::Result<Vec<ShellSession>> {
+		let (reply_tx, reply_rx) = oneshot::channel();
+
+		self.commands.send(Command::Request(Reply::Sessions(reply_tx)))
+			.map_err(|_| Error::from(ErrorKind::NotConnected))?;
 
-	Ok((input_tx, output_rx))
+		match reply_rx.await {
+			Ok(sessions) => Ok(sessions?),
+			Err(_) => Err(ErrorKind::ConnectionAborted.into()),
+		}
+	}
+
+	pub async fn end(self) {
+		let _ = self.commands.send(Command::End);
+		let _ = self.task.await;
+	}
+}
+
+#[derive(Clone, Debug)]
+pub struct Channel {
+	id: ChannelId,
+	commands: UnboundedSender<Command>,
+}
+
+impl Channel {
+	pub fn send(&self, input: Input) -> io::Result<()> {
+		self.commands.send(Command::Input(self.id, input))
+			.map_err(|_| Error::from(ErrorKind::NotConnected))
+	}
+}
+
+#[derive(Debug)]
+enum Command {
+	Open(ChannelId, Connection, UnboundedSender<Output>),
+	Input(ChannelId, Input),
+	Request(Reply),
+	End,
+}
+
+// where the answer to a request goes, which also says what was asked
+#[derive(Debug)]
+enum Reply {
+	Listeners(ListenersReply),
+	Sessions(SessionsReply),
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -134,21 +492,94 @@ pub enum Input {
 	Continue,
 	Stop,
 	Winch(u16, u16),
+	/// Nothing more will be sent, but the channel stays open for output.
+	Eof,
 	End,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Debug)]
 pub enum Output {
+	Opened,
 	Data(Vec<u8>),
-	Died(u8),
+	/// Belongs on stderr rather than stdout.
+	ErrorData(Vec<u8>),
+	Died(ExitStatus),
+	/// The session the shell on this channel can be attached to again by.
+	Session(SessionId),
+	/// The connection was lost, and the shell on this channel will carry on
+	/// once it is resumed.
+	Reconnecting,
+	Reconnected,
+	Error(RemoteError),
+	/// Nothing more will be received, but the channel stays open for input.
+	Eof,
 	Closed,
+	/// A connection the server accepted on a listening channel.
+	Accepted(Channel, UnboundedReceiver<Output>),
 }
 
+/// An error reported by the server, for the whole session or one channel.
 #[derive(Clone, Debug, PartialEq)]
+pub struct RemoteError {
+	pub code: ErrorCode,
+	pub message: String,
+}
+
+impl RemoteError {
+	/// The status the client exits with when this error ends it, following
+	/// the BSD `sysexits.h` conventions.
+	pub fn exit_status(&self) -> i32 {
+		match self.code {
+			ErrorCode::Other => 1,
+			ErrorCode::InvalidMessage => 65, // EX_DATAERR
+			ErrorCode::NotFound => 66, // EX_NOINPUT
+			ErrorCode::ConnectionRefused | ErrorCode::ListenFailed => 69, // EX_UNAVAILABLE
+			ErrorCode::SpawnFailed => 71, // EX_OSERR
+			ErrorCode::VersionMismatch => 76, // EX_PROTOCOL
+			ErrorCode::PermissionDenied => 77, // EX_NOPERM
+		}
+	}
+}
+
+impl fmt::Display for RemoteError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.message)
+	}
+}
+
+impl error::Error for RemoteError {}
+
+impl From<RemoteError> for Error {
+	fn from(from: RemoteError) -> Self {
+		let kind = match from.code {
+			ErrorCode::ConnectionRefused => ErrorKind::ConnectionRefused,
+			ErrorCode::PermissionDenied => ErrorKind::PermissionDenied,
+			_ => ErrorKind::Other,
+		};
+
+		Error::new(kind, from)
+	}
+}
+
+/// Picks the exit status for an error that ends the client.
+pub fn exit_status(error: &Error) -> i32 {
+	match error.get_ref().and_then(|inner| inner.downcast_ref::<RemoteError>()) {
+		Some(remote) => remote.exit_status(),
+		None if error.kind() == ErrorKind::PermissionDenied => 77, // EX_NOPERM
+		None => 1,
+	}
+}
+
+#[derive(Clone, Debug)]
 pub struct ConnectionSettings {
 	pub repl: Repl,
-	pub connection: Connection,
-	pub key: String,
+	pub credentials: Credentials,
+}
+
+#[derive(Clone, Debug)]
+pub enum Credentials {
+	Key(String),
+	Identity(SigningKey),
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -158,7 +589,7 @@ pub struct Repl {
 }
 
 impl Repl {
-	fn domain(&self) -> String {
+	pub fn domain(&self) -> String {
 		format!("{}.{}.repl.co", self.name, self.user)
 	}
 }
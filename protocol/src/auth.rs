@@ -0,0 +1,101 @@
+use crate::PROTOCOL;
+
+use std::convert::TryFrom;
+
+use ed25519_dalek::{ Signature, Signer, SigningKey, VerifyingKey };
+use hmac::{ Hmac, Mac };
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The length of the nonce the server sends in a `Challenge`.
+pub const NONCE_LENGTH: usize = 32;
+
+/// Computes the response to a challenge, which is an HMAC-SHA256 of the nonce
+/// keyed with the shared key.
+pub fn respond(key: &[u8], nonce: &[u8]) -> Vec<u8> {
+	let mut mac = HmacSha256::new_from_slice(key)
+		.expect("HMAC accepts keys of any length");
+	mac.update(nonce);
+	mac.finalize().into_bytes().to_vec()
+}
+
+/// Checks a response against the expected one in constant time.
+pub fn verify(key: &[u8], nonce: &[u8], response: &[u8]) -> bool {
+	let mut mac = HmacSha256::new_from_slice(key)
+		.expect("HMAC accepts keys of any length");
+	mac.update(nonce);
+	mac.verify_slice(response).is_ok()
+}
+
+/// Signs a challenge with an Ed25519 key.
+pub fn sign(key: &SigningKey, nonce: &[u8]) -> Vec<u8> {
+	key.sign(&signed_data(nonce)).to_bytes().to_vec()
+}
+
+/// Checks an Ed25519 signature over a challenge. This says nothing about
+/// whether the key itself is allowed to log in.
+pub fn verify_signature(public_key: &[u8], nonce: &[u8], signature: &[u8]) -> bool {
+	let public_key = match <[u8; 32]>::try_from(public_key) {
+		Ok(bytes) => match VerifyingKey::from_bytes(&bytes) {
+			Ok(key) => key,
+			Err(_) => return false,
+		},
+		Err(_) => return false,
+	};
+
+	let signature = match Signature::from_slice(signature) {
+		Ok(signature) => signature,
+		Err(_) => return false,
+	};
+
+	public_key.verify_strict(&signed_data(nonce), &signature).is_ok()
+}
+
+// the protocol name is prepended so that a signature can never be mistaken
+// for one made for another purpose with the same key
+fn signed_data(nonce: &[u8]) -> Vec<u8> {
+	[ PROTOCOL.as_bytes(), &[ 0 ], nonce ].concat()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn respond_verify() {
+		let nonce = [ 7; NONCE_LENGTH ];
+		let response = respond(b"hunter2", &nonce);
+
+		assert!(verify(b"hunter2", &nonce, &response));
+		assert!(!verify(b"hunter3", &nonce, &response));
+		assert!(!verify(b"hunter2", &[ 8; NONCE_LENGTH ], &response));
+		assert!(!verify(b"hunter2", &nonce, &response[1..]));
+	}
+
+	#[test]
+	fn sign_verify() {
+		let key = SigningKey::from_bytes(&[ 3; 32 ]);
+		let public_key = key.verifying_key().to_bytes();
+		let nonce = [ 7; NONCE_LENGTH ];
+		let signature = sign(&key, &nonce);
+
+		assert!(verify_signature(&public_key, &nonce, &signature));
+		assert!(!verify_signature(&public_key, &[ 8; NONCE_LENGTH ], &signature));
+		assert!(!verify_signature(&[ 0; 32 ], &nonce, &signature));
+		assert!(!verify_signature(&public_key, &nonce, &signature[1..]));
+	}
+
+	#[test]
+	fn rfc4231_case_2() {
+		assert_eq!(
+			respond(b"Jefe", b"what do ya want for nothing?"),
+			[
+				0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e,
+				0x6a, 0x04, 0x24, 0x26, 0x08, 0x95, 0x75, 0xc7,
+				0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83,
+				0x9d, 0xec, 0x58, 0xb9, 0x64, 0xec, 0x38, 0x43,
+			],
+		);
+	}
+}
@@ -0,0 +1,183 @@
+use crate::VERSION;
+use crate::message::{ Message, MessageType };
+
+use std::error;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+	AwaitingHello,
+	AwaitingChallenge,
+	AwaitingAuthentication,
+	Authenticating,
+	Established,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HandshakeError {
+	VersionMismatch(u8, u8),
+	Unexpected(State, MessageType),
+}
+
+impl fmt::Display for HandshakeError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::VersionMismatch(major, minor) => write!(
+				f, "protocol version {}.{} does not match {}.{}",
+				major, minor, VERSION.0, VERSION.1,
+			),
+			Self::Unexpected(state, message_type) => write!(
+				f, "unexpected {:?} message while in state {:?}",
+				message_type, state,
+			),
+		}
+	}
+}
+
+impl error::Error for HandshakeError {}
+
+/// Tracks the progress of a connection through the handshake.
+///
+/// Both ends feed every message they send or receive into `observe`, in the
+/// order they are exchanged, so that the client and server always agree on
+/// the state of the connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Handshake {
+	state: State,
+}
+
+impl Handshake {
+	pub fn new() -> Self {
+		Self { state: State::AwaitingHello }
+	}
+
+	pub fn state(&self) -> State {
+		self.state
+	}
+
+	pub fn observe(&mut self, message: &Message) -> Result<State, HandshakeError> {
+		use State::*;
+
+		self.state = match (self.state, message) {
+			(_, Message::EndSession) | (_, Message::Error(_, _)) => self.state,
+			(AwaitingHello, Message::Hello(major, minor)) => {
+				if (*major, *minor) != VERSION {
+					return Err(HandshakeError::VersionMismatch(*major, *minor))
+				}
+
+				AwaitingChallenge
+			},
+			(AwaitingChallenge, Message::Challenge(_)) => AwaitingAuthentication,
+			(AwaitingAuthentication, Message::Authenticate(_)) => Authenticating,
+			(Authenticating, Message::Authentication(true)) => Established,
+			(Authenticating, Message::Authentication(false)) => AwaitingChallenge,
+			(Established, message) if !message.is_handshake() => Established,
+			(state, message) =>
+				return Err(HandshakeError::Unexpected(state, message.message_type())),
+		};
+
+		Ok(self.state)
+	}
+}
+
+impl Default for Handshake {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::message::{ Connection, Credential };
+
+	#[test]
+	fn full_handshake() {
+		let mut handshake = Handshake::new();
+
+		assert_eq!(
+			handshake.observe(&Message::Hello(VERSION.0, VERSION.1)),
+			Ok(State::AwaitingChallenge),
+		);
+		assert_eq!(
+			handshake.observe(&Message::Challenge(vec![ 0; 32 ])),
+			Ok(State::AwaitingAuthentication),
+		);
+		assert_eq!(
+			handshake.observe(&Message::Authenticate(Credential::Key(vec![ 1; 32 ]))),
+			Ok(State::Authenticating),
+		);
+		assert_eq!(
+			handshake.observe(&Message::Authentication(true)),
+			Ok(State::Established),
+		);
+		assert_eq!(
+			handshake.observe(&Message::ChannelOpen(1, Connection::Port(8080))),
+			Ok(State::Established),
+		);
+		assert_eq!(
+			handshake.observe(&Message::SocketInput(1, vec![ 1, 2, 3 ])),
+			Ok(State::Established),
+		);
+	}
+
+	#[test]
+	fn version_mismatch() {
+		let mut handshake = Handshake::new();
+
+		assert_eq!(
+			handshake.observe(&Message::Hello(VERSION.0, VERSION.1 + 1)),
+			Err(HandshakeError::VersionMismatch(VERSION.0, VERSION.1 + 1)),
+		);
+		assert_eq!(handshake.state(), State::AwaitingHello);
+	}
+
+	#[test]
+	fn failed_authentication_needs_new_challenge() {
+		let mut handshake = Handshake::new();
+
+		handshake.observe(&Message::Hello(VERSION.0, VERSION.1)).unwrap();
+		handshake.observe(&Message::Challenge(vec![ 0; 32 ])).unwrap();
+		handshake.observe(&Message::Authenticate(Credential::Key(vec![ 1; 32 ]))).unwrap();
+
+		assert_eq!(
+			handshake.observe(&Message::Authentication(false)),
+			Ok(State::AwaitingChallenge),
+		);
+		assert_eq!(
+			handshake.observe(&Message::Authenticate(Credential::Key(vec![ 1; 32 ]))),
+			Err(HandshakeError::Unexpected(
+				State::AwaitingChallenge,
+				MessageType::Authenticate,
+			)),
+		);
+		assert_eq!(
+			handshake.observe(&Message::Challenge(vec![ 2; 32 ])),
+			Ok(State::AwaitingAuthentication),
+		);
+	}
+
+	#[test]
+	fn rejects_out_of_order() {
+		let mut handshake = Handshake::new();
+
+		assert_eq!(
+			handshake.observe(&Message::ChannelOpen(1, Connection::Shell(vec![]))),
+			Err(HandshakeError::Unexpected(
+				State::AwaitingHello,
+				MessageType::ChannelOpen,
+			)),
+		);
+
+		handshake.observe(&Message::Hello(VERSION.0, VERSION.1)).unwrap();
+
+		assert_eq!(
+			handshake.observe(&Message::TerminalInput(1, vec![])),
+			Err(HandshakeError::Unexpected(
+				State::AwaitingChallenge,
+				MessageType::TerminalInput,
+			)),
+		);
+	}
+}
@@ -0,0 +1,16 @@
+pub mod auth;
+pub mod keys;
+
+mod handshake;
+mod message;
+mod resume;
+
+pub use handshake::{ Handshake, HandshakeError, State };
+pub use message::{
+	ChannelId, Connection, Credential, ErrorCode, ExitStatus, Listener, Message, MessageType,
+	RequestId, SessionId, ShellSession, Transport, SERVER_CHANNEL,
+};
+pub use resume::{ Replay, ReplayError, ACK_INTERVAL };
+
+pub const PROTOCOL: &str = "autobahn-websocket-tunnel";
+pub const VERSION: (u8, u8) = (0, 19);
@@ -0,0 +1,875 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::net::IpAddr;
+
+use minicbor::decode::{ Decode, Decoder, Error as DecodeError };
+use minicbor::encode::{ Encode, Encoder, Error as EncodeError };
+use minicbor::encode::write::Write;
+
+use num_enum::{ FromPrimitive, IntoPrimitive, TryFromPrimitive };
+
+/// The tag sent at the start of every message.
+///
+/// Tags are never reused or renumbered, so that a peer speaking an older
+/// version can still decode `Hello` and `Error` and be told to upgrade. New
+/// message types take the next free number.
+#[derive(Clone, Copy, Debug, PartialEq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum MessageType {
+	Ack = 29,
+	Authenticate = 0,
+	Authentication = 1,
+	Challenge = 17,
+	ChannelAccept = 22,
+	ChannelClose = 15,
+	ChannelError = 19,
+	ChannelOpen = 16,
+	ChannelOpened = 23,
+	ChildDeath = 2,
+	EndSession = 4,
+	Error = 5,
+	ErrorOutput = 25,
+	Hello = 6,
+	InputEnd = 24,
+	ListListeners = 20,
+	ListSessions = 27,
+	Listeners = 21,
+	OutputEnd = 33,
+	Resume = 30,
+	ResumeToken = 31,
+	Resumed = 32,
+	SessionAttached = 26,
+	Sessions = 28,
+	SignalContinue = 7,
+	SignalStop = 8,
+	SignalWinch = 9,
+	SocketInput = 11,
+	SocketOutput = 12,
+	TerminalInput = 13,
+	TerminalOutput = 14,
+}
+
+impl<'b> Decode<'b> for MessageType {
+	fn decode(d: &mut Decoder<'b>) -> Result<Self, DecodeError> {
+		let index = d.u8()?;
+		index.try_into()
+			.map_err(|_| DecodeError::UnknownVariant(index.into()))
+	}
+}
+
+impl Encode for MessageType {
+	fn encode<W: Write>(
+		&self,
+		e: &mut Encoder<W>,
+	) -> Result<(), EncodeError<W::Error>> {
+		e.u8((*self).into()).map(|_| ())
+	}
+}
+
+/// What a channel is connected to on the server.
+///
+/// Encoded as a kind, followed by the host and port for forwarding or
+/// listening, or the arguments and environment for a shell or command.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Connection {
+	/// A shell, with variables like `TERM` and `LANG` from the client to add
+	/// to its environment.
+	Shell(Vec<(String, String)>),
+	Port(u16),
+	/// A UDP port, with each `SocketInput` and `SocketOutput` carrying exactly
+	/// one datagram.
+	UdpPort(u16),
+	/// A port the server listens on, announcing each connection it accepts
+	/// with a `ChannelAccept`.
+	ReversePort(u16),
+	/// A TCP port on another host the server can reach, subject to its
+	/// allowlist. `Port` is the same as this with `127.0.0.1`.
+	Remote(String, u16),
+	/// A command run without a terminal, with its arguments and the client's
+	/// variables. Its stdin and stdout are carried by `SocketInput` and
+	/// `SocketOutput`, and its stderr by `ErrorOutput`.
+	Exec(Vec<String>, Vec<(String, String)>),
+	/// A shell already running on the server, which behaves like `Shell` once
+	/// attached.
+	Attach(SessionId),
+}
+
+impl fmt::Display for Connection {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Shell(_) => write!(f, "shell"),
+			Self::Port(port) => write!(f, "port {}", port),
+			Self::UdpPort(port) => write!(f, "UDP port {}", port),
+			Self::ReversePort(port) => write!(f, "listening on port {}", port),
+			Self::Remote(host, port) => write!(f, "port {} on {}", port, host),
+			Self::Exec(args, _) => write!(f, "command {}", args.join(" ")),
+			Self::Attach(session) => write!(f, "session {}", session),
+		}
+	}
+}
+
+impl<'b> Decode<'b> for Connection {
+	fn decode(d: &mut Decoder<'b>) -> Result<Self, DecodeError> {
+		match d.u8()? {
+			0 => Ok(Self::Shell(decode_env(d)?)),
+			1 => Ok(Self::Port(d.u16()?)),
+			2 => Ok(Self::UdpPort(d.u16()?)),
+			3 => Ok(Self::ReversePort(d.u16()?)),
+			4 => Ok(Self::Remote(d.str()?.into(), d.u16()?)),
+			5 => {
+				let len = d.array()?
+					.ok_or(DecodeError::Message("indefinite arguments"))?;
+				let args = (0..len)
+					.map(|_| Ok(d.str()?.into()))
+					.collect::<Result<_, DecodeError>>()?;
+
+				Ok(Self::Exec(args, decode_env(d)?))
+			},
+			6 => Ok(Self::Attach(d.u32()?)),
+			kind => Err(DecodeError::UnknownVariant(kind.into())),
+		}
+	}
+}
+
+impl Encode for Connection {
+	fn encode<W: Write>(
+		&self,
+		e: &mut Encoder<W>,
+	) -> Result<(), EncodeError<W::Error>> {
+		match self {
+			Self::Shell(env) => {
+				e.u8(0)?;
+				encode_env(env, e)?;
+			},
+			Self::Port(port) => { e.u8(1)?.u16(*port)?; },
+			Self::UdpPort(port) => { e.u8(2)?.u16(*port)?; },
+			Self::ReversePort(port) => { e.u8(3)?.u16(*port)?; },
+			Self::Remote(host, port) => { e.u8(4)?.str(host)?.u16(*port)?; },
+			Self::Exec(args, env) => {
+				e.u8(5)?.array(args.len() as u64)?;
+				for arg in args {
+					e.str(arg)?;
+				}
+				encode_env(env, e)?;
+			},
+			Self::Attach(session) => { e.u8(6)?.u32(*session)?; },
+		}
+
+		Ok(())
+	}
+}
+
+// environment variables are a map of names to values
+fn decode_env(d: &mut Decoder<'_>) -> Result<Vec<(String, String)>, DecodeError> {
+	let len = d.map()?
+		.ok_or(DecodeError::Message("indefinite environment"))?;
+
+	(0..len)
+		.map(|_| Ok((d.str()?.into(), d.str()?.into())))
+		.collect()
+}
+
+fn encode_env<W: Write>(
+	env: &[(String, String)],
+	e: &mut Encoder<W>,
+) -> Result<(), EncodeError<W::Error>> {
+	e.map(env.len() as u64)?;
+	for (name, value) in env {
+		e.str(name)?.str(value)?;
+	}
+
+	Ok(())
+}
+
+/// Why a session or channel was refused or torn down.
+#[derive(Clone, Copy, Debug, PartialEq, IntoPrimitive, FromPrimitive)]
+#[repr(u8)]
+pub enum ErrorCode {
+	/// Anything without a more specific code, including codes added by a
+	/// newer peer.
+	#[num_enum(default)]
+	Other = 0,
+	VersionMismatch = 1,
+	InvalidMessage = 2,
+	PermissionDenied = 3,
+	ConnectionRefused = 4,
+	SpawnFailed = 5,
+	ListenFailed = 6,
+	/// There is no such session to attach to.
+	NotFound = 7,
+}
+
+impl<'b> Decode<'b> for ErrorCode {
+	fn decode(d: &mut Decoder<'b>) -> Result<Self, DecodeError> {
+		Ok(d.u8()?.into())
+	}
+}
+
+impl Encode for ErrorCode {
+	fn encode<W: Write>(
+		&self,
+		e: &mut Encoder<W>,
+	) -> Result<(), EncodeError<W::Error>> {
+		e.u8((*self).into()).map(|_| ())
+	}
+}
+
+/// Proof of identity sent in response to a `Challenge`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Credential {
+	/// An HMAC of the nonce, keyed with the shared key.
+	Key(Vec<u8>),
+	/// An Ed25519 public key, and its signature over the nonce.
+	PublicKey(Vec<u8>, Vec<u8>),
+}
+
+impl<'b> Decode<'b> for Credential {
+	fn decode(d: &mut Decoder<'b>) -> Result<Self, DecodeError> {
+		match d.u8()? {
+			0 => Ok(Self::Key(d.bytes()?.into())),
+			1 => Ok(Self::PublicKey(d.bytes()?.into(), d.bytes()?.into())),
+			index => Err(DecodeError::UnknownVariant(index.into())),
+		}
+	}
+}
+
+impl Encode for Credential {
+	fn encode<W: Write>(
+		&self,
+		e: &mut Encoder<W>,
+	) -> Result<(), EncodeError<W::Error>> {
+		match self {
+			Self::Key(mac) => { e.u8(0)?.bytes(mac)?; },
+			Self::PublicKey(key, signature) => { e.u8(1)?.bytes(key)?.bytes(signature)?; },
+		}
+
+		Ok(())
+	}
+}
+
+/// How a process on the server finished.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExitStatus {
+	Exited(u8),
+	/// Killed by a signal. Numbers differ between systems, so the server sends
+	/// the name it knows it by too.
+	Signaled {
+		signal: u8,
+		name: String,
+		core_dumped: bool,
+	},
+}
+
+impl ExitStatus {
+	/// The status a shell would report, with 128 added to a signal's number.
+	pub fn code(&self) -> i32 {
+		match self {
+			Self::Exited(code) => (*code).into(),
+			Self::Signaled { signal, .. } => 128 + i32::from(*signal),
+		}
+	}
+}
+
+impl fmt::Display for ExitStatus {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Exited(code) => write!(f, "exited with code {}", code),
+			Self::Signaled { name, core_dumped: false, .. } => write!(f, "killed by {}", name),
+			Self::Signaled { name, core_dumped: true, .. } => write!(f, "killed by {} (core dumped)", name),
+		}
+	}
+}
+
+impl<'b> Decode<'b> for ExitStatus {
+	fn decode(d: &mut Decoder<'b>) -> Result<Self, DecodeError> {
+		match d.u8()? {
+			0 => Ok(Self::Exited(d.u8()?)),
+			1 => Ok(Self::Signaled { signal: d.u8()?, name: d.str()?.into(), core_dumped: d.bool()? }),
+			kind => Err(DecodeError::UnknownVariant(kind.into())),
+		}
+	}
+}
+
+impl Encode for ExitStatus {
+	fn encode<W: Write>(
+		&self,
+		e: &mut Encoder<W>,
+	) -> Result<(), EncodeError<W::Error>> {
+		match self {
+			Self::Exited(code) => { e.u8(0)?.u8(*code)?; },
+			Self::Signaled { signal, name, core_dumped } => {
+				e.u8(1)?.u8(*signal)?.str(name)?.bool(*core_dumped)?;
+			},
+		}
+
+		Ok(())
+	}
+}
+
+/// The transport protocol of a socket.
+#[derive(Clone, Copy, Debug, PartialEq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Transport {
+	Tcp = 0,
+	Udp = 1,
+}
+
+impl fmt::Display for Transport {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Tcp => write!(f, "tcp"),
+			Self::Udp => write!(f, "udp"),
+		}
+	}
+}
+
+impl<'b> Decode<'b> for Transport {
+	fn decode(d: &mut Decoder<'b>) -> Result<Self, DecodeError> {
+		let index = d.u8()?;
+		index.try_into()
+			.map_err(|_| DecodeError::UnknownVariant(index.into()))
+	}
+}
+
+impl Encode for Transport {
+	fn encode<W: Write>(
+		&self,
+		e: &mut Encoder<W>,
+	) -> Result<(), EncodeError<W::Error>> {
+		e.u8((*self).into()).map(|_| ())
+	}
+}
+
+/// A socket on the server that others can reach: a TCP socket listening for
+/// connections, or a UDP socket bound to a port.
+///
+/// Encoded as an array, so that fields added later can be skipped by older
+/// peers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Listener {
+	pub address: IpAddr,
+	pub port: u16,
+	pub uid: u32,
+	/// The owning process, if the server could find it.
+	pub pid: Option<u32>,
+	pub process: Option<String>,
+	pub transport: Transport,
+}
+
+const LISTENER_FIELDS: u64 = 6;
+
+impl<'b> Decode<'b> for Listener {
+	fn decode(d: &mut Decoder<'b>) -> Result<Self, DecodeError> {
+		let fields = d.array()?.ok_or(DecodeError::Message("indefinite listener"))?;
+		if fields < LISTENER_FIELDS {
+			return Err(DecodeError::Message("listener is missing fields"))
+		}
+
+		let bytes = d.bytes()?;
+		let address: IpAddr = if let Ok(octets) = TryInto::<[u8; 4]>::try_into(bytes) {
+			octets.into()
+		} else if let Ok(octets) = TryInto::<[u8; 16]>::try_into(bytes) {
+			octets.into()
+		} else {
+			return Err(DecodeError::Message("invalid listener address"))
+		};
+
+		let listener = Self {
+			address,
+			port: d.u16()?,
+			uid: d.u32()?,
+			pid: d.decode()?,
+			process: d.decode::<Option<&str>>()?.map(String::from),
+			transport: d.decode()?,
+		};
+
+		for _ in LISTENER_FIELDS..fields {
+			d.skip()?;
+		}
+
+		Ok(listener)
+	}
+}
+
+impl Encode for Listener {
+	fn encode<W: Write>(
+		&self,
+		e: &mut Encoder<W>,
+	) -> Result<(), EncodeError<W::Error>> {
+		e.array(LISTENER_FIELDS)?;
+
+		match self.address {
+			IpAddr::V4(address) => e.bytes(&address.octets())?,
+			IpAddr::V6(address) => e.bytes(&address.octets())?,
+		};
+
+		e.u16(self.port)?
+			.u32(self.uid)?
+			.encode(self.pid)?
+			.encode(self.process.as_deref())?
+			.encode(self.transport)?;
+
+		Ok(())
+	}
+}
+
+/// A shell on the server that outlives the connection which started it.
+///
+/// Encoded as an array, like `Listener`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShellSession {
+	pub id: SessionId,
+	pub pid: u32,
+	pub command: String,
+	/// Whether a client is attached to it now.
+	pub attached: bool,
+	/// When it started, in seconds since the Unix epoch.
+	pub started: u64,
+}
+
+const SHELL_SESSION_FIELDS: u64 = 5;
+
+impl<'b> Decode<'b> for ShellSession {
+	fn decode(d: &mut Decoder<'b>) -> Result<Self, DecodeError> {
+		let fields = d.array()?.ok_or(DecodeError::Message("indefinite session"))?;
+		if fields < SHELL_SESSION_FIELDS {
+			return Err(DecodeError::Message("session is missing fields"))
+		}
+
+		let session = Self {
+			id: d.u32()?,
+			pid: d.u32()?,
+			command: d.str()?.into(),
+			attached: d.bool()?,
+			started: d.u64()?,
+		};
+
+		for _ in SHELL_SESSION_FIELDS..fields {
+			d.skip()?;
+		}
+
+		Ok(session)
+	}
+}
+
+impl Encode for ShellSession {
+	fn encode<W: Write>(
+		&self,
+		e: &mut Encoder<W>,
+	) -> Result<(), EncodeError<W::Error>> {
+		e.array(SHELL_SESSION_FIELDS)?
+			.u32(self.id)?
+			.u32(self.pid)?
+			.str(&self.command)?
+			.bool(self.attached)?
+			.u64(self.started)?;
+
+		Ok(())
+	}
+}
+
+/// Identifies a shell session on the server, across connections.
+pub type SessionId = u32;
+
+/// Matches a response to the request that asked for it.
+pub type RequestId = u32;
+
+/// Identifies one channel multiplexed over a session.
+///
+/// Channels are allocated by the client when it sends `ChannelOpen`, or by the
+/// server when it sends `ChannelAccept`, and are finished by a `ChannelClose`
+/// from either end.
+pub type ChannelId = u32;
+
+/// Set in the ids of channels the server allocates, and never in the client's.
+pub const SERVER_CHANNEL: ChannelId = 1 << 31;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+	/// The sender has received this many sequenced messages, and the other
+	/// side can stop keeping them for `Resume`.
+	Ack(u64),
+	Authenticate(Credential),
+	Authentication(bool),
+	Challenge(Vec<u8>),
+	/// A connection accepted by the listening channel, given a new channel.
+	ChannelAccept(ChannelId, ChannelId),
+	ChannelClose(ChannelId),
+	ChannelError(ChannelId, ErrorCode, String),
+	ChannelOpen(ChannelId, Connection),
+	/// The server's side of a channel the client opened is ready. Failures
+	/// are reported with `ChannelError` instead.
+	ChannelOpened(ChannelId),
+	ChildDeath(ChannelId, ExitStatus),
+	EndSession,
+	Error(ErrorCode, String),
+	/// What a command wrote to stderr.
+	ErrorOutput(ChannelId, Vec<u8>),
+	Hello(u8, u8),
+	/// The client has nothing more to send a command's stdin or a socket,
+	/// which is closed for writing. The channel stays open for its output.
+	InputEnd(ChannelId),
+	ListListeners(RequestId),
+	ListSessions(RequestId),
+	Listeners(RequestId, Vec<Listener>),
+	/// Nothing more will come from the server's socket, as whatever is at
+	/// the other end of it stopped writing. The channel stays open for input.
+	OutputEnd(ChannelId),
+	/// Sent by the client instead of using a newly authenticated connection,
+	/// to carry on with the session that has this token, having received
+	/// this many sequenced messages of it.
+	Resume(Vec<u8>, u64),
+	/// The token the client can `Resume` this session with if the connection
+	/// drops, sent once authentication succeeds.
+	ResumeToken(Vec<u8>),
+	/// The session was resumed, and the server had received this many
+	/// sequenced messages of it. Whatever either side sent after those
+	/// follows again.
+	Resumed(u64),
+	/// The shell on a channel is this session, and can be attached to again
+	/// by its id once the channel is gone.
+	SessionAttached(ChannelId, SessionId),
+	Sessions(RequestId, Vec<ShellSession>),
+	SignalContinue(ChannelId),
+	SignalStop(ChannelId),
+	SignalWinch(ChannelId, u16, u16),
+	SocketInput(ChannelId, Vec<u8>),
+	SocketOutput(ChannelId, Vec<u8>),
+	TerminalInput(ChannelId, Vec<u8>),
+	TerminalOutput(ChannelId, Vec<u8>),
+}
+
+impl Message {
+	pub fn message_type(&self) -> MessageType {
+		match self {
+			Self::Ack(_) => MessageType::Ack,
+			Self::Authenticate(_) => MessageType::Authenticate,
+			Self::Authentication(_) => MessageType::Authentication,
+			Self::Challenge(_) => MessageType::Challenge,
+			Self::ChannelAccept(_, _) => MessageType::ChannelAccept,
+			Self::ChannelClose(_) => MessageType::ChannelClose,
+			Self::ChannelError(_, _, _) => MessageType::ChannelError,
+			Self::ChannelOpen(_, _) => MessageType::ChannelOpen,
+			Self::ChannelOpened(_) => MessageType::ChannelOpened,
+			Self::ChildDeath(_, _) => MessageType::ChildDeath,
+			Self::EndSession => MessageType::EndSession,
+			Self::Error(_, _) => MessageType::Error,
+			Self::ErrorOutput(_, _) => MessageType::ErrorOutput,
+			Self::Hello(_, _) => MessageType::Hello,
+			Self::InputEnd(_) => MessageType::InputEnd,
+			Self::ListListeners(_) => MessageType::ListListeners,
+			Self::ListSessions(_) => MessageType::ListSessions,
+			Self::Listeners(_, _) => MessageType::Listeners,
+			Self::OutputEnd(_) => MessageType::OutputEnd,
+			Self::Resume(_, _) => MessageType::Resume,
+			Self::ResumeToken(_) => MessageType::ResumeToken,
+			Self::Resumed(_) => MessageType::Resumed,
+			Self::SessionAttached(_, _) => MessageType::SessionAttached,
+			Self::Sessions(_, _) => MessageType::Sessions,
+			Self::SignalContinue(_) => MessageType::SignalContinue,
+			Self::SignalStop(_) => MessageType::SignalStop,
+			Self::SignalWinch(_, _, _) => MessageType::SignalWinch,
+			Self::SocketInput(_, _) => MessageType::SocketInput,
+			Self::SocketOutput(_, _) => MessageType::SocketOutput,
+			Self::TerminalInput(_, _) => MessageType::TerminalInput,
+			Self::TerminalOutput(_, _) => MessageType::TerminalOutput,
+		}
+	}
+
+	pub fn channel(&self) -> Option<ChannelId> {
+		match self {
+			Self::ChannelAccept(channel, _) |
+			Self::ChannelClose(channel) |
+			Self::ChannelError(channel, _, _) |
+			Self::ChannelOpen(channel, _) |
+			Self::ChannelOpened(channel) |
+			Self::ChildDeath(channel, _) |
+			Self::ErrorOutput(channel, _) |
+			Self::InputEnd(channel) |
+			Self::OutputEnd(channel) |
+			Self::SessionAttached(channel, _) |
+			Self::SignalContinue(channel) |
+			Self::SignalStop(channel) |
+			Self::SignalWinch(channel, _, _) |
+			Self::SocketInput(channel, _) |
+			Self::SocketOutput(channel, _) |
+			Self::TerminalInput(channel, _) |
+			Self::TerminalOutput(channel, _) => Some(*channel),
+			_ => None,
+		}
+	}
+
+	pub fn is_handshake(&self) -> bool {
+		matches!(
+			self,
+			Self::Authenticate(_) | Self::Authentication(_) |
+			Self::Challenge(_) | Self::Hello(_, _)
+		)
+	}
+
+	/// Whether the message counts towards the numbering `Resume` relies on.
+	/// Only those about the connection itself rather than the session don't.
+	pub fn is_sequenced(&self) -> bool {
+		!self.is_handshake() && !matches!(
+			self,
+			Self::Ack(_) | Self::Error(_, _) |
+			Self::Resume(_, _) | Self::ResumeToken(_) | Self::Resumed(_)
+		)
+	}
+}
+
+impl<'b> Decode<'b> for Message {
+	fn decode(d: &mut Decoder<'b>) -> Result<Self, DecodeError> {
+		use MessageType::*;
+
+		Ok(match d.decode::<MessageType>()? {
+			Ack => Self::Ack(d.u64()?),
+			Authenticate => Self::Authenticate(d.decode()?),
+			Authentication => Self::Authentication(d.bool()?),
+			Challenge => Self::Challenge(d.bytes()?.into()),
+			ChannelAccept => Self::ChannelAccept(d.u32()?, d.u32()?),
+			ChannelClose => Self::ChannelClose(d.u32()?),
+			ChannelError => Self::ChannelError(d.u32()?, d.decode()?, d.str()?.into()),
+			ChannelOpen => Self::ChannelOpen(d.u32()?, d.decode()?),
+			ChannelOpened => Self::ChannelOpened(d.u32()?),
+			ChildDeath => Self::ChildDeath(d.u32()?, d.decode()?),
+			EndSession => Self::EndSession,
+			Error => Self::Error(d.decode()?, d.str()?.into()),
+			ErrorOutput => Self::ErrorOutput(d.u32()?, d.bytes()?.into()),
+			Hello => Self::Hello(d.u8()?, d.u8()?),
+			InputEnd => Self::InputEnd(d.u32()?),
+			ListListeners => Self::ListListeners(d.u32()?),
+			ListSessions => Self::ListSessions(d.u32()?),
+			Listeners => Self::Listeners(d.u32()?, d.decode()?),
+			OutputEnd => Self::OutputEnd(d.u32()?),
+			Resume => Self::Resume(d.bytes()?.into(), d.u64()?),
+			ResumeToken => Self::ResumeToken(d.bytes()?.into()),
+			Resumed => Self::Resumed(d.u64()?),
+			SessionAttached => Self::SessionAttached(d.u32()?, d.u32()?),
+			Sessions => Self::Sessions(d.u32()?, d.decode()?),
+			SignalContinue => Self::SignalContinue(d.u32()?),
+			SignalStop => Self::SignalStop(d.u32()?),
+			SignalWinch => Self::SignalWinch(d.u32()?, d.u16()?, d.u16()?),
+			SocketInput => Self::SocketInput(d.u32()?, d.bytes()?.into()),
+			SocketOutput => Self::SocketOutput(d.u32()?, d.bytes()?.into()),
+			TerminalInput => Self::TerminalInput(d.u32()?, d.bytes()?.into()),
+			TerminalOutput => Self::TerminalOutput(d.u32()?, d.bytes()?.into()),
+		})
+	}
+}
+
+impl Encode for Message {
+	fn encode<W: Write>(
+		&self,
+		e: &mut Encoder<W>,
+	) -> Result<(), EncodeError<W::Error>> {
+		e.encode(self.message_type())?;
+
+		if let Some(channel) = self.channel() {
+			e.u32(channel)?;
+		}
+
+		match self {
+			Self::Ack(received) => { e.u64(*received)?; },
+			Self::Authenticate(data) => { e.encode(data)?; },
+			Self::Authentication(data) => { e.bool(*data)?; },
+			Self::Challenge(data) => { e.bytes(data)?; },
+			Self::ChannelAccept(_, accepted) => { e.u32(*accepted)?; },
+			Self::ChannelError(_, code, text) => { e.encode(code)?.str(text)?; },
+			Self::ChannelOpen(_, data) => { e.encode(data)?; },
+			Self::ChildDeath(_, status) => { e.encode(status)?; },
+			Self::Error(code, text) => { e.encode(code)?.str(text)?; },
+			Self::ErrorOutput(_, data) => { e.bytes(data)?; },
+			Self::Hello(m, i) => { e.u8(*m)?; e.u8(*i)?; },
+			Self::ListListeners(request) => { e.u32(*request)?; },
+			Self::ListSessions(request) => { e.u32(*request)?; },
+			Self::Listeners(request, listeners) => { e.u32(*request)?.encode(listeners)?; },
+			Self::Resume(token, received) => { e.bytes(token)?.u64(*received)?; },
+			Self::ResumeToken(token) => { e.bytes(token)?; },
+			Self::Resumed(received) => { e.u64(*received)?; },
+			Self::SessionAttached(_, session) => { e.u32(*session)?; },
+			Self::Sessions(request, sessions) => { e.u32(*request)?.encode(sessions)?; },
+			Self::SignalWinch(_, w, h) => { e.u16(*w)?; e.u16(*h)?; },
+			Self::SocketInput(_, data) => { e.bytes(data)?; },
+			Self::SocketOutput(_, data) => { e.bytes(data)?; },
+			Self::TerminalInput(_, data) => { e.bytes(data)?; },
+			Self::TerminalOutput(_, data) => { e.bytes(data)?; },
+			_ => (),
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::net::{ Ipv4Addr, Ipv6Addr };
+
+	fn round_trip(message: Message) {
+		let data = minicbor::to_vec(&message).unwrap();
+		let decoded: Message = minicbor::decode(data.as_slice()).unwrap();
+
+		assert_eq!(decoded, message);
+		assert_eq!(decoded.message_type(), message.message_type());
+	}
+
+	#[test]
+	fn round_trip_all() {
+		round_trip(Message::Ack(64));
+		round_trip(Message::Authenticate(Credential::Key(vec![ 0xab; 32 ])));
+		round_trip(Message::Authenticate(
+			Credential::PublicKey(vec![ 0xcd; 32 ], vec![ 0xef; 64 ])
+		));
+		round_trip(Message::Authentication(true));
+		round_trip(Message::Authentication(false));
+		round_trip(Message::Challenge(vec![ 0x5a; 32 ]));
+		round_trip(Message::ChannelAccept(3, SERVER_CHANNEL | 1));
+		round_trip(Message::ChannelClose(7));
+		round_trip(Message::ChannelError(
+			4, ErrorCode::PermissionDenied, "permission denied for port 22".into(),
+		));
+		round_trip(Message::ChannelError(5, ErrorCode::SpawnFailed, String::new()));
+		round_trip(Message::ChannelOpen(1, Connection::Shell(vec![])));
+		round_trip(Message::ChannelOpen(1, Connection::Shell(vec![
+			("TERM".into(), "xterm-256color".into()),
+			("LANG".into(), "en_GB.UTF-8".into()),
+		])));
+		round_trip(Message::ChannelOpen(u32::MAX, Connection::Port(8080)));
+		round_trip(Message::ChannelOpen(2, Connection::UdpPort(53)));
+		round_trip(Message::ChannelOpen(3, Connection::ReversePort(5000)));
+		round_trip(Message::ChannelOpen(4, Connection::Remote("db.internal".into(), 5432)));
+		round_trip(Message::ChannelOpen(4, Connection::Remote("fd00::1".into(), 443)));
+		round_trip(Message::ChannelOpen(5, Connection::Exec(
+			vec![ "make".into(), "test".into() ],
+			vec![ ("LANG".into(), "C.UTF-8".into()) ],
+		)));
+		round_trip(Message::ChannelOpen(5, Connection::Exec(vec![], vec![])));
+		round_trip(Message::ChannelOpen(6, Connection::Attach(3)));
+		round_trip(Message::ChannelOpened(3));
+		round_trip(Message::ChildDeath(1, ExitStatus::Exited(0)));
+		round_trip(Message::ChildDeath(1, ExitStatus::Exited(255)));
+		round_trip(Message::ChildDeath(1, ExitStatus::Signaled {
+			signal: 11,
+			name: "SIGSEGV".into(),
+			core_dumped: true,
+		}));
+		round_trip(Message::EndSession);
+		round_trip(Message::Error(ErrorCode::VersionMismatch, "please upgrade".into()));
+		round_trip(Message::ErrorOutput(5, b"error: no rule to make target".to_vec()));
+		round_trip(Message::Hello(0, 7));
+		round_trip(Message::InputEnd(5));
+		round_trip(Message::ListListeners(3));
+		round_trip(Message::Listeners(3, vec![]));
+		round_trip(Message::Listeners(4, vec![
+			Listener {
+				address: Ipv4Addr::LOCALHOST.into(),
+				port: 8080,
+				uid: 1000,
+				pid: Some(4242),
+				process: Some("node".into()),
+				transport: Transport::Tcp,
+			},
+			Listener {
+				address: Ipv6Addr::UNSPECIFIED.into(),
+				port: 53,
+				uid: 0,
+				pid: None,
+				process: None,
+				transport: Transport::Udp,
+			},
+		]));
+		round_trip(Message::ListSessions(5));
+		round_trip(Message::OutputEnd(2));
+		round_trip(Message::Resume(vec![ 0x3c; 16 ], u64::MAX));
+		round_trip(Message::ResumeToken(vec![ 0x3c; 16 ]));
+		round_trip(Message::Resumed(0));
+		round_trip(Message::SessionAttached(6, 3));
+		round_trip(Message::Sessions(5, vec![]));
+		round_trip(Message::Sessions(5, vec![
+			ShellSession {
+				id: 3,
+				pid: 4242,
+				command: "/bin/bash".into(),
+				attached: false,
+				started: 1_700_000_000,
+			},
+		]));
+		round_trip(Message::SignalContinue(1));
+		round_trip(Message::SignalStop(1));
+		round_trip(Message::SignalWinch(1, 80, 24));
+		round_trip(Message::SocketInput(2, vec![ 0, 1, 2, 255 ]));
+		round_trip(Message::SocketOutput(3, vec![]));
+		round_trip(Message::TerminalInput(1, b"ls -la\r".to_vec()));
+		round_trip(Message::TerminalOutput(1, b"\x1b[2J".to_vec()));
+	}
+
+	#[test]
+	fn channel_ids() {
+		assert_eq!(Message::SocketInput(42, vec![]).channel(), Some(42));
+		assert_eq!(Message::SignalWinch(9, 80, 24).channel(), Some(9));
+		assert_eq!(Message::ChannelAccept(3, SERVER_CHANNEL).channel(), Some(3));
+		assert_eq!(Message::Hello(0, 3).channel(), None);
+		assert_eq!(Message::EndSession.channel(), None);
+	}
+
+	#[test]
+	fn legacy_tags() {
+		// a 0.2 client must still be able to reach the version check
+		assert_eq!(minicbor::to_vec(Message::Hello(0, 2)).unwrap(), [ 6, 0, 2 ]);
+		assert_eq!(
+			minicbor::to_vec(Message::Error(ErrorCode::VersionMismatch, String::new())).unwrap(),
+			[ 5, 1, 0x60 ],
+		);
+	}
+
+	#[test]
+	fn unknown_error_code() {
+		let data = [ 5, 0x18, 200, 0x62, b'h', b'i' ];
+
+		assert_eq!(
+			minicbor::decode::<Message>(&data).unwrap(),
+			Message::Error(ErrorCode::Other, "hi".into()),
+		);
+	}
+
+	#[test]
+	fn listener_extra_fields() {
+		// a newer server may send more fields than we know about
+		let mut data = vec![ 21, 9, 0x81, 0x87, 0x44, 127, 0, 0, 1 ];
+		data.extend_from_slice(&minicbor::to_vec(80u16).unwrap());
+		data.extend_from_slice(&[ 0, 0xf6, 0xf6, 1, 0x63, b'n', b'e', b'w' ]);
+
+		assert_eq!(
+			minicbor::decode::<Message>(&data).unwrap(),
+			Message::Listeners(9, vec![ Listener {
+				address: Ipv4Addr::LOCALHOST.into(),
+				port: 80,
+				uid: 0,
+				pid: None,
+				process: None,
+				transport: Transport::Udp,
+			} ]),
+		);
+	}
+
+	#[test]
+	fn exit_statuses() {
+		let segfault = ExitStatus::Signaled { signal: 11, name: "SIGSEGV".into(), core_dumped: true };
+		let killed = ExitStatus::Signaled { signal: 9, name: "SIGKILL".into(), core_dumped: false };
+
+		assert_eq!(ExitStatus::Exited(3).code(), 3);
+		assert_eq!(segfault.code(), 139);
+		assert_eq!(ExitStatus::Exited(3).to_string(), "exited with code 3");
+		assert_eq!(segfault.to_string(), "killed by SIGSEGV (core dumped)");
+		assert_eq!(killed.to_string(), "killed by SIGKILL");
+	}
+
+	#[test]
+	fn unknown_type() {
+		let data = minicbor::to_vec(255u8).unwrap();
+
+		assert!(minicbor::decode::<Message>(data.as_slice()).is_err());
+	}
+}
@@ -0,0 +1,203 @@
+use crate::message::Message;
+
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+
+/// How many sequenced messages are received between each `Ack`.
+pub const ACK_INTERVAL: u64 = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplayError {
+	/// The other side claims to have received messages that were never sent.
+	AheadOf(u64, u64),
+	/// The other side needs messages it had already acknowledged.
+	Forgotten(u64, u64),
+}
+
+impl fmt::Display for ReplayError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::AheadOf(received, sent) => write!(
+				f, "{} messages were received, but only {} were sent",
+				received, sent,
+			),
+			Self::Forgotten(received, acknowledged) => write!(
+				f, "{} messages were received, but {} were already acknowledged",
+				received, acknowledged,
+			),
+		}
+	}
+}
+
+impl error::Error for ReplayError {}
+
+/// Keeps a session's place in the message stream, so that it can carry on
+/// over a new connection when one drops.
+///
+/// Sequenced messages are numbered from 1 in each direction by the order
+/// they are sent in. Each side keeps what it sent until the other
+/// acknowledges it with `Ack`, and after `Resume` they send again whatever
+/// the other side is missing, so nothing is lost or received twice.
+#[derive(Debug, Default)]
+pub struct Replay {
+	sent: u64,
+	unacknowledged: VecDeque<Vec<u8>>,
+	unacknowledged_size: usize,
+	received: u64,
+	acknowledged: u64,
+}
+
+impl Replay {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Encodes a message to send, keeping it until it is acknowledged if it
+	/// is sequenced.
+	pub fn record(&mut self, message: &Message) -> Vec<u8> {
+		let data = minicbor::to_vec(message).unwrap();
+
+		if message.is_sequenced() {
+			self.sent += 1;
+			self.unacknowledged_size += data.len();
+			self.unacknowledged.push_back(data.clone());
+		}
+
+		data
+	}
+
+	/// Counts a message that was received, returning the `Ack` to send when
+	/// one is due.
+	pub fn receive(&mut self, message: &Message) -> Option<Message> {
+		if !message.is_sequenced() {
+			return None
+		}
+
+		self.received += 1;
+
+		if self.received - self.acknowledged >= ACK_INTERVAL {
+			self.acknowledged = self.received;
+			Some(Message::Ack(self.received))
+		} else {
+			None
+		}
+	}
+
+	/// How many sequenced messages were received.
+	pub fn received(&self) -> u64 {
+		self.received
+	}
+
+	/// The size of the messages kept for the other side, in bytes.
+	pub fn unacknowledged_size(&self) -> usize {
+		self.unacknowledged_size
+	}
+
+	/// Forgets the messages the other side says it has received. Stale
+	/// acknowledgements are ignored.
+	pub fn acknowledge(&mut self, received: u64) -> Result<(), ReplayError> {
+		if received > self.sent {
+			return Err(ReplayError::AheadOf(received, self.sent))
+		}
+
+		while self.forgotten() < received {
+			if let Some(data) = self.unacknowledged.pop_front() {
+				self.unacknowledged_size -= data.len();
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Picks up after the other side received `received` messages, returning
+	/// the ones to send again, in order.
+	///
+	/// Both sides tell each other what they received when resuming, which
+	/// counts as an acknowledgement in each direction.
+	pub fn resume(&mut self, received: u64) -> Result<impl Iterator<Item = &[u8]>, ReplayError> {
+		if received < self.forgotten() {
+			return Err(ReplayError::Forgotten(received, self.forgotten()))
+		}
+
+		self.acknowledge(received)?;
+		self.acknowledged = self.received;
+
+		Ok(self.unacknowledged.iter().map(Vec::as_slice))
+	}
+
+	// how many of the messages sent have been let go of
+	fn forgotten(&self) -> u64 {
+		self.sent - self.unacknowledged.len() as u64
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::message::ErrorCode;
+
+	fn data(channel: u32) -> Message {
+		Message::TerminalOutput(channel, vec![ 0; 4 ])
+	}
+
+	fn encoded(messages: &[Message]) -> Vec<Vec<u8>> {
+		messages.iter().map(|message| minicbor::to_vec(message).unwrap()).collect()
+	}
+
+	#[test]
+	fn keeps_sequenced() {
+		let mut replay = Replay::new();
+
+		replay.record(&data(1));
+		replay.record(&Message::Ack(3));
+		replay.record(&Message::Error(ErrorCode::Other, String::new()));
+		replay.record(&data(2));
+
+		let kept: Vec<_> = replay.resume(0).unwrap().map(Vec::from).collect();
+		assert_eq!(kept, encoded(&[ data(1), data(2) ]));
+	}
+
+	#[test]
+	fn resumes_after_received() {
+		let mut replay = Replay::new();
+
+		for channel in 1..=5 {
+			replay.record(&data(channel));
+		}
+		replay.acknowledge(2).unwrap();
+		assert_eq!(replay.unacknowledged_size(), 3 * minicbor::to_vec(data(1)).unwrap().len());
+
+		// a stale acknowledgement changes nothing
+		replay.acknowledge(1).unwrap();
+
+		let kept: Vec<_> = replay.resume(4).unwrap().map(Vec::from).collect();
+		assert_eq!(kept, encoded(&[ data(5) ]));
+
+		assert_eq!(replay.resume(1).err(), Some(ReplayError::Forgotten(1, 4)));
+		assert_eq!(replay.resume(6).err(), Some(ReplayError::AheadOf(6, 5)));
+		assert_eq!(replay.resume(5).unwrap().count(), 0);
+	}
+
+	#[test]
+	fn acknowledges_periodically() {
+		let mut replay = Replay::new();
+
+		assert_eq!(replay.receive(&Message::Resumed(0)), None);
+
+		for _ in 1..ACK_INTERVAL {
+			assert_eq!(replay.receive(&data(1)), None);
+		}
+		assert_eq!(replay.receive(&data(1)), Some(Message::Ack(ACK_INTERVAL)));
+		assert_eq!(replay.receive(&data(1)), None);
+		assert_eq!(replay.received(), ACK_INTERVAL + 1);
+
+		// resuming tells the other side as much
+		let _ = replay.resume(0);
+		for _ in 1..ACK_INTERVAL {
+			assert_eq!(replay.receive(&data(1)), None);
+		}
+		assert_eq!(replay.receive(&data(1)), Some(Message::Ack(2 * ACK_INTERVAL + 1)));
+	}
+}
@@ -0,0 +1,346 @@
+use autobahn_protocol::{ auth, keys, Connection, Credential };
+
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Decides whether a client's answer to a challenge lets it in, and what it
+/// may do once it is.
+#[derive(Clone, Debug)]
+pub struct Authenticator {
+	key: Option<String>,
+	key_permissions: Permissions,
+	authorized_keys: Option<PathBuf>,
+}
+
+impl Authenticator {
+	pub fn new(
+		key: Option<String>,
+		key_permissions: Permissions,
+		authorized_keys: Option<PathBuf>,
+	) -> Self {
+		Self { key, key_permissions, authorized_keys }
+	}
+
+	/// Checks a credential against the nonce it answers, returning who it
+	/// belongs to.
+	pub fn authenticate(&self, nonce: &[u8], credential: &Credential) -> Option<Identity> {
+		match credential {
+			Credential::Key(response) => self.key.as_ref()
+				.filter(|key| auth::verify(key.as_bytes(), nonce, response))
+				.map(|_| Identity {
+					name: "shared key".into(),
+					credential: CredentialId::SharedKey,
+					permissions: self.key_permissions.clone(),
+				}),
+			Credential::PublicKey(public_key, signature) => {
+				if !auth::verify_signature(public_key, nonce, signature) {
+					return None
+				}
+
+				self.authorized_keys()
+					.into_iter()
+					.find(|(key, _)| key.as_bytes() == public_key.as_slice())
+					.map(|(_, identity)| identity)
+			},
+		}
+	}
+
+	// read on every attempt, so that removing a key takes effect immediately
+	fn authorized_keys(&self) -> Vec<(keys::VerifyingKey, Identity)> {
+		let path = match self.authorized_keys {
+			Some(ref path) => path,
+			None => return vec![],
+		};
+
+		let data = match fs::read_to_string(path) {
+			Ok(data) => data,
+			Err(_) => {
+				warn!("failed to read authorized keys from {}", path.display());
+				return vec![]
+			},
+		};
+
+		let mut out = vec![];
+		for (index, line) in data.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue
+			}
+
+			match parse_authorized_key(line) {
+				Ok(key) => out.push(key),
+				Err(error) => warn!("authorized keys line {}: {}", index + 1, error),
+			}
+		}
+
+		out
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+	/// What to call the client in logs, which several credentials may share.
+	pub name: String,
+	pub credential: CredentialId,
+	pub permissions: Permissions,
+}
+
+/// The credential a client authenticated with, which tells what it started
+/// apart from what others did.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum CredentialId {
+	SharedKey,
+	PublicKey(Vec<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Permissions {
+	pub shell: bool,
+	pub ports: PortSet,
+}
+
+impl Permissions {
+	pub fn permits(&self, connection: &Connection) -> bool {
+		match connection {
+			Connection::Shell(_) | Connection::Exec(_, _) | Connection::Attach(_) => self.shell,
+			// the destination is checked against the allowlist once it's resolved
+			Connection::Port(port) | Connection::UdpPort(port) | Connection::ReversePort(port) |
+			Connection::Remote(_, port) => self.ports.contains(*port),
+		}
+	}
+}
+
+impl Default for Permissions {
+	fn default() -> Self {
+		Self { shell: true, ports: PortSet::All }
+	}
+}
+
+/// A set of ports, written as `*` or as a comma-separated list of ports and
+/// inclusive ranges such as `5432,8000-8100`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PortSet {
+	All,
+	Only(Vec<RangeInclusive<u16>>),
+}
+
+impl PortSet {
+	pub fn contains(&self, port: u16) -> bool {
+		match self {
+			Self::All => true,
+			Self::Only(ranges) => ranges.iter().any(|range| range.contains(&port)),
+		}
+	}
+}
+
+impl FromStr for PortSet {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, String> {
+		if s.trim() == "*" {
+			return Ok(Self::All)
+		}
+
+		let mut ranges = vec![];
+		for part in s.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+			let (start, end) = match part.find('-') {
+				Some(split) => (&part[..split], &part[(split + 1)..]),
+				None => (part, part),
+			};
+
+			let start = u16::from_str(start.trim())
+				.map_err(|_| format!("invalid port {}", start))?;
+			let end = u16::from_str(end.trim())
+				.map_err(|_| format!("invalid port {}", end))?;
+
+			if start > end {
+				return Err(format!("invalid port range {}", part))
+			}
+
+			ranges.push(start..=end);
+		}
+
+		Ok(Self::Only(ranges))
+	}
+}
+
+// parses a line like `no-shell,ports="5432" ssh-ed25519 AAAA... ci-bot`
+fn parse_authorized_key(line: &str) -> Result<(keys::VerifyingKey, Identity), String> {
+	let (options, key) = if line.starts_with("ssh-") {
+		("", line)
+	} else {
+		split_unquoted(line, |c| c.is_whitespace())
+	};
+
+	let (key, name) = keys::parse_public_key(key.trim_start())
+		.map_err(|error| error.to_string())?;
+	let mut permissions = Permissions::default();
+
+	let mut options = options;
+	while !options.is_empty() {
+		let (option, rest) = split_unquoted(options, |c| c == ',');
+		options = rest;
+
+		let (name, value) = match option.find('=') {
+			Some(split) => (&option[..split], Some(option[(split + 1)..].trim_matches('"'))),
+			None => (option, None),
+		};
+
+		match (name, value) {
+			("no-shell", None) => permissions.shell = false,
+			("no-port-forwarding", None) => permissions.ports = PortSet::Only(vec![]),
+			("ports", Some(ports)) => permissions.ports = PortSet::from_str(ports)?,
+			_ => return Err(format!("unknown option {}", option)),
+		}
+	}
+
+	let credential = CredentialId::PublicKey(key.as_bytes().to_vec());
+
+	Ok((key, Identity { name, credential, permissions }))
+}
+
+// splits at the first separator that isn't inside double quotes
+fn split_unquoted(s: &str, separator: impl Fn(char) -> bool) -> (&str, &str) {
+	let mut quoted = false;
+
+	for (index, c) in s.char_indices() {
+		if c == '"' {
+			quoted = !quoted;
+		} else if !quoted && separator(c) {
+			return (&s[..index], &s[(index + c.len_utf8())..])
+		}
+	}
+
+	(s, "")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIGq52vCZxJiPzADebw4Zu4GW+kN/+tb795ys+sm/ZEr8";
+
+	fn identity(line: &str) -> Result<Identity, String> {
+		parse_authorized_key(line).map(|(key, identity)| {
+			assert_eq!(key, keys::parse_public_key(KEY).unwrap().0);
+			identity
+		})
+	}
+
+	#[test]
+	fn port_sets() {
+		assert_eq!("*".parse(), Ok(PortSet::All));
+		assert_eq!(" * ".parse(), Ok(PortSet::All));
+		assert_eq!("5432".parse(), Ok(PortSet::Only(vec![ 5432..=5432 ])));
+		assert_eq!(
+			"5432, 8000 - 8100,,9000".parse(),
+			Ok(PortSet::Only(vec![ 5432..=5432, 8000..=8100, 9000..=9000 ])),
+		);
+		assert_eq!("0-65535".parse(), Ok(PortSet::Only(vec![ 0..=65535 ])));
+
+		// nothing listed allows nothing
+		assert_eq!("".parse(), Ok(PortSet::Only(vec![])));
+		assert_eq!(" , ".parse(), Ok(PortSet::Only(vec![])));
+	}
+
+	#[test]
+	fn malformed_port_sets() {
+		for bad in &[ "http", "65536", "-1", "80-", "-80", "100-80", "1-2-3", "80 443", "*,80" ] {
+			assert!(bad.parse::<PortSet>().is_err(), "{}", bad);
+		}
+	}
+
+	#[test]
+	fn port_set_contains() {
+		let ports: PortSet = "22,8000-8100".parse().unwrap();
+
+		assert!(ports.contains(22));
+		assert!(ports.contains(8000));
+		assert!(ports.contains(8050));
+		assert!(ports.contains(8100));
+		assert!(!ports.contains(21));
+		assert!(!ports.contains(8101));
+
+		assert!(PortSet::All.contains(0));
+		assert!(!PortSet::Only(vec![]).contains(22));
+	}
+
+	#[test]
+	fn permits() {
+		let permissions = Permissions { shell: false, ports: "5432".parse().unwrap() };
+
+		assert!(!permissions.permits(&Connection::Shell(Vec::new())));
+		assert!(!permissions.permits(&Connection::Attach(1)));
+		assert!(!permissions.permits(&Connection::Exec(vec![ "true".into() ], Vec::new())));
+		assert!(permissions.permits(&Connection::Port(5432)));
+		assert!(permissions.permits(&Connection::UdpPort(5432)));
+		assert!(permissions.permits(&Connection::ReversePort(5432)));
+		assert!(permissions.permits(&Connection::Remote("db.internal".into(), 5432)));
+		assert!(!permissions.permits(&Connection::Port(5433)));
+		assert!(!permissions.permits(&Connection::Remote("db.internal".into(), 22)));
+
+		let default = Permissions::default();
+		assert!(default.permits(&Connection::Shell(Vec::new())));
+		assert!(default.permits(&Connection::Port(1)));
+	}
+
+	#[test]
+	fn authorized_key_without_options() {
+		let (key, _) = keys::parse_public_key(KEY).unwrap();
+		assert_eq!(identity(&format!("{} ci bot", KEY)), Ok(Identity {
+			name: "ci bot".into(),
+			credential: CredentialId::PublicKey(key.as_bytes().to_vec()),
+			permissions: Permissions::default(),
+		}));
+		assert_eq!(identity(KEY).map(|identity| identity.name), Ok(String::new()));
+	}
+
+	#[test]
+	fn authorized_key_options() {
+		assert_eq!(
+			identity(&format!("no-shell {} ci", KEY)).map(|identity| identity.permissions),
+			Ok(Permissions { shell: false, ports: PortSet::All }),
+		);
+		assert_eq!(
+			identity(&format!("no-port-forwarding {} ci", KEY)).map(|identity| identity.permissions),
+			Ok(Permissions { shell: true, ports: PortSet::Only(vec![]) }),
+		);
+		assert_eq!(
+			identity(&format!("ports=\"5432, 8000-8100\",no-shell  {} ci", KEY)).map(|identity| identity.permissions),
+			Ok(Permissions { shell: false, ports: PortSet::Only(vec![ 5432..=5432, 8000..=8100 ]) }),
+		);
+		assert_eq!(
+			identity(&format!("ports=22 {} ci", KEY)).map(|identity| identity.permissions),
+			Ok(Permissions { shell: true, ports: PortSet::Only(vec![ 22..=22 ]) }),
+		);
+	}
+
+	#[test]
+	fn bad_authorized_keys() {
+		assert_eq!(
+			identity(&format!("no-pty {} ci", KEY)),
+			Err("unknown option no-pty".into()),
+		);
+		assert_eq!(
+			identity(&format!("no-shell=yes {} ci", KEY)),
+			Err("unknown option no-shell=yes".into()),
+		);
+		assert_eq!(identity(&format!("ports {} ci", KEY)), Err("unknown option ports".into()));
+		assert!(identity(&format!("ports=\"http\" {} ci", KEY)).is_err());
+		assert!(identity("no-shell ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQ== ci").is_err());
+		assert!(identity("ssh-ed25519 !!! ci").is_err());
+	}
+
+	#[test]
+	fn unquoted_splits() {
+		assert_eq!(split_unquoted("a,b,c", |c| c == ','), ("a", "b,c"));
+		assert_eq!(split_unquoted("ports=\"1, 2\",no-shell", |c| c == ','), ("ports=\"1, 2\"", "no-shell"));
+		assert_eq!(
+			split_unquoted("ports=\"1 2\" ssh-ed25519 AAAA", char::is_whitespace),
+			("ports=\"1 2\"", "ssh-ed25519 AAAA"),
+		);
+		assert_eq!(split_unquoted("abc", |c| c == ','), ("abc", ""));
+	}
+}
@@ -1,23 +1,259 @@
+use crate::auth::{ Permissions, PortSet };
+use crate::port::Preferences;
+
+use std::collections::BTreeMap;
 use std::fs;
+use std::io::{ self, ErrorKind, IsTerminal };
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
 
 use serde_derive::Deserialize;
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct DotReplit {
+	#[serde(default)]
 	pub autobahn: Config,
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
 pub struct Config {
 	pub port: Option<u16>,
+	pub authorized_keys: Option<PathBuf>,
+	pub permissions: Option<PermissionsConfig>,
+	/// Hosts outside the repl that clients may forward to, for everyone.
+	/// Loopback and unspecified addresses are the repl itself, and bypass it.
+	#[serde(default)]
+	pub destinations: Vec<String>,
+	#[serde(default)]
+	pub shell: ShellConfig,
+	pub port_selection: Option<PortSelectionConfig>,
+	#[serde(default, rename = "route")]
+	pub routes: Vec<Route>,
+	/// How traffic for `port` is forwarded, including anything that isn't HTTP.
+	#[serde(flatten)]
+	pub forwarding: Forwarding,
+}
+
+/// Sends HTTP requests whose path starts with `path`, and optionally whose
+/// `Host` is `host`, to `port`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Route {
+	#[serde(default = "root_path")]
+	pub path: String,
+	pub host: Option<String>,
+	pub port: u16,
+	/// Removes `path` from the request before it is forwarded.
+	#[serde(default)]
+	pub strip_prefix: bool,
+	#[serde(flatten)]
+	pub forwarding: Forwarding,
+}
+
+fn root_path() -> String {
+	"/".into()
+}
+
+/// What a backend is told about the client behind each connection.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct Forwarding {
+	/// Adds `X-Forwarded-For`, `X-Forwarded-Proto` and `Forwarded` to HTTP requests.
+	#[serde(default = "enabled")]
+	pub forwarded_headers: bool,
+	/// Starts each connection to the backend with a PROXY protocol header.
+	pub proxy_protocol: Option<ProxyProtocol>,
+}
+
+impl Default for Forwarding {
+	fn default() -> Self {
+		Self { forwarded_headers: true, proxy_protocol: None }
+	}
+}
+
+fn enabled() -> bool {
+	true
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+	V1,
+	V2,
+}
+
+/// How remote shells are started.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ShellConfig {
+	#[serde(default = "default_shell")]
+	pub command: PathBuf,
+	#[serde(default)]
+	pub args: Vec<String>,
+	/// Starts the shell as a login shell, with `-` in front of its name.
+	#[serde(default)]
+	pub login: bool,
+	#[serde(default)]
+	pub inherit_env: InheritEnv,
+	/// Variables to set on top of the inherited ones.
+	#[serde(default)]
+	pub env: BTreeMap<String, String>,
+	pub cwd: Option<PathBuf>,
+}
+
+impl Default for ShellConfig {
+	fn default() -> Self {
+		Self {
+			command: default_shell(),
+			args: Vec::new(),
+			login: false,
+			inherit_env: InheritEnv::default(),
+			env: BTreeMap::new(),
+			cwd: None,
+		}
+	}
+}
+
+fn default_shell() -> PathBuf {
+	"/bin/bash".into()
+}
+
+/// Which of the server's own variables a shell starts with: all or none of
+/// them, or only those named.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum InheritEnv {
+	All(bool),
+	Only(Vec<String>),
+}
+
+impl Default for InheritEnv {
+	fn default() -> Self {
+		Self::All(true)
+	}
+}
+
+impl InheritEnv {
+	pub fn inherits(&self, name: &str) -> bool {
+		match self {
+			Self::All(all) => *all,
+			Self::Only(names) => names.iter().any(|inherited| inherited == name),
+		}
+	}
+}
+
+/// What clients authenticating with the shared key may open; keys in
+/// `authorized_keys` carry their own options instead.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct PermissionsConfig {
+	pub shell: Option<bool>,
+	pub ports: Option<String>,
+}
+
+impl PermissionsConfig {
+	pub fn to_permissions(&self) -> Result<Permissions, String> {
+		let mut permissions = Permissions::default();
+
+		if let Some(shell) = self.shell {
+			permissions.shell = shell;
+		}
+		if let Some(ref ports) = self.ports {
+			permissions.ports = PortSet::from_str(ports)?;
+		}
+
+		Ok(permissions)
+	}
+}
+
+/// How to pick a port to proxy when several processes are listening and
+/// `port` isn't set.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct PortSelectionConfig {
+	pub process: Option<String>,
+	pub ports: Option<String>,
+	pub choose: Option<PortChoice>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PortChoice {
+	Ask,
+	Lowest,
+}
+
+impl PortSelectionConfig {
+	pub fn to_preferences(&self) -> Result<Preferences, String> {
+		Ok(Preferences {
+			process: self.process.clone(),
+			ports: self.ports.as_deref().map(PortSet::from_str).transpose()?,
+			// only ask when someone is there to answer
+			ask: match self.choose {
+				Some(choice) => choice == PortChoice::Ask,
+				None => io::stdin().is_terminal(),
+			},
+		})
+	}
 }
 
-pub fn load_config(file: &str) -> Option<Config> {
-	fs::read(file)
-		.map_err(|_| ())
-		.and_then(|data| String::from_utf8(data).map_err(|_| ()))
-		.and_then(|data| toml::from_str(data.as_str()).map_err(|_| ()))
-		.map(|result: DotReplit| Some(result))
-		.unwrap_or(None)
-		.map(|data| data.autobahn)
+/// Reads the `[autobahn]` section of a `.replit` file, which may leave it
+/// out. A file that can't be read or parsed is an error rather than the
+/// defaults, as those let the shared key do anything.
+pub fn load_config(file: &str) -> io::Result<Config> {
+	let data = fs::read_to_string(file)?;
+	let mut config = toml::from_str::<DotReplit>(&data)
+		.map_err(|error| io::Error::new(ErrorKind::InvalidData, error))?
+		.autobahn;
+
+	// paths in the config are relative to the config file
+	if let Some(dir) = Path::new(file).parent() {
+		config.authorized_keys = config.authorized_keys
+			.map(|path| dir.join(path));
+		config.shell.cwd = config.shell.cwd
+			.map(|path| dir.join(path));
+	}
+
+	Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::env;
+	use std::process;
+
+	// writes `data` to a file of its own, returning its path
+	fn config_file(name: &str, data: &str) -> String {
+		let dir = env::temp_dir().join(format!("autobahn-config-{}-{}", process::id(), name));
+		fs::create_dir_all(&dir).unwrap();
+
+		let path = dir.join(".replit");
+		fs::write(&path, data).unwrap();
+		path.to_str().unwrap().into()
+	}
+
+	#[test]
+	fn without_section() {
+		let file = config_file("empty", "run = \"npm start\"\n");
+		assert_eq!(load_config(&file).unwrap(), Config::default());
+	}
+
+	#[test]
+	fn relative_paths() {
+		let file = config_file("paths", "[autobahn]\nauthorized_keys = \"keys\"\n[autobahn.shell]\ncwd = \"work\"\n");
+		let dir = Path::new(&file).parent().unwrap();
+
+		let config = load_config(&file).unwrap();
+		assert_eq!(config.authorized_keys, Some(dir.join("keys")));
+		assert_eq!(config.shell.cwd, Some(dir.join("work")));
+	}
+
+	#[test]
+	fn unreadable() {
+		assert_eq!(load_config("/nonexistent/.replit").unwrap_err().kind(), ErrorKind::NotFound);
+
+		// a mistake mustn't leave the shared key with the default permissions
+		let file = config_file("typo", "[autobahn.permissions]\nshell = \"no\"\n");
+		assert_eq!(load_config(&file).unwrap_err().kind(), ErrorKind::InvalidData);
+
+		let file = config_file("syntax", "[autobahn\nport = 80\n");
+		assert_eq!(load_config(&file).unwrap_err().kind(), ErrorKind::InvalidData);
+	}
 }
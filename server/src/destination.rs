@@ -0,0 +1,210 @@
+use crate::auth::PortSet;
+
+use std::net::{ IpAddr, SocketAddr };
+use std::str::FromStr;
+
+/// The hosts outside the repl that clients may forward to. Each entry is
+/// written `host:ports`, where `host` is a name, a `*.`-prefixed domain, an
+/// address, a CIDR block or `*`, IPv6 ones in brackets, and `ports` is a
+/// `PortSet`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Destinations(Vec<Destination>);
+
+impl Destinations {
+	pub fn new(entries: &[String]) -> Result<Self, String> {
+		entries.iter()
+			.map(|entry| entry.parse().map_err(|error| format!("{}: {}", entry, error)))
+			.collect::<Result<_, _>>()
+			.map(Self)
+	}
+
+	/// Whether `address`, which `name` resolved to, may be connected to.
+	pub fn permits(&self, name: &str, address: SocketAddr) -> bool {
+		self.0.iter().any(|destination| destination.matches(name, address))
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Destination {
+	host: Host,
+	ports: PortSet,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Host {
+	Any,
+	Name(String),
+	/// Any name ending with `.` and this.
+	Domain(String),
+	Network(IpAddr, u8),
+}
+
+impl Destination {
+	fn matches(&self, name: &str, address: SocketAddr) -> bool {
+		let name = normalize(name);
+
+		self.ports.contains(address.port()) && match self.host {
+			Host::Any => true,
+			Host::Name(ref host) => name == *host,
+			Host::Domain(ref domain) => name.ends_with(&format!(".{}", domain)),
+			Host::Network(network, prefix) => in_network(address.ip(), network, prefix),
+		}
+	}
+}
+
+impl FromStr for Destination {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, String> {
+		let s = s.trim();
+		let (host, ports) = if let Some(rest) = s.strip_prefix('[') {
+			let (host, ports) = rest.split_once(']')
+				.ok_or_else(|| "unclosed bracket".to_string())?;
+			(host, ports.strip_prefix(':'))
+		} else {
+			match s.rsplit_once(':') {
+				Some((host, ports)) => (host, Some(ports)),
+				None => (s, None),
+			}
+		};
+
+		let ports = ports.ok_or_else(|| "missing ports".to_string())?;
+		if ports.trim().is_empty() {
+			return Err("missing ports".into())
+		}
+
+		Ok(Self { host: host.parse()?, ports: ports.parse()? })
+	}
+}
+
+impl FromStr for Host {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, String> {
+		if s == "*" {
+			return Ok(Self::Any)
+		}
+		if let Some(domain) = s.strip_prefix("*.") {
+			return Ok(Self::Domain(normalize(domain)))
+		}
+
+		let (address, prefix) = match s.split_once('/') {
+			Some((address, prefix)) => (address, Some(prefix)),
+			None => (s, None),
+		};
+
+		match (address.parse::<IpAddr>(), prefix) {
+			(Ok(address), prefix) => {
+				let bits = if address.is_ipv4() { 32 } else { 128 };
+				let prefix = match prefix {
+					Some(prefix) => prefix.parse::<u8>()
+						.ok()
+						.filter(|prefix| *prefix <= bits)
+						.ok_or_else(|| format!("invalid prefix length {}", prefix))?,
+					None => bits,
+				};
+
+				Ok(Self::Network(address, prefix))
+			},
+			(Err(_), None) if !s.is_empty() => Ok(Self::Name(normalize(s))),
+			_ => Err(format!("invalid host {}", s)),
+		}
+	}
+}
+
+// names are case-insensitive, and may be written fully qualified
+fn normalize(name: &str) -> String {
+	name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn in_network(address: IpAddr, network: IpAddr, prefix: u8) -> bool {
+	let (address, network, bits) = match (address.to_canonical(), network) {
+		(IpAddr::V4(address), IpAddr::V4(network)) =>
+			(u32::from(address) as u128, u32::from(network) as u128, 32),
+		(IpAddr::V6(address), IpAddr::V6(network)) =>
+			(u128::from(address), u128::from(network), 128),
+		_ => return false,
+	};
+
+	let shift = bits - prefix as u32;
+	address.checked_shr(shift).unwrap_or(0) == network.checked_shr(shift).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn permits(entries: &[ &str ], name: &str, address: &str) -> bool {
+		let entries: Vec<String> = entries.iter().map(|entry| entry.to_string()).collect();
+		Destinations::new(&entries).unwrap().permits(name, address.parse().unwrap())
+	}
+
+	#[test]
+	fn parse() {
+		assert_eq!("*:*".parse(), Ok(Destination { host: Host::Any, ports: "*".parse().unwrap() }));
+		assert_eq!(
+			"*.Example.com.:80,443".parse::<Destination>().map(|destination| destination.host),
+			Ok(Host::Domain("example.com".into())),
+		);
+		assert_eq!(
+			"DB.internal:5432".parse::<Destination>().map(|destination| destination.host),
+			Ok(Host::Name("db.internal".into())),
+		);
+		assert_eq!(
+			"10.0.0.0/8:1-1024".parse::<Destination>().map(|destination| destination.host),
+			Ok(Host::Network("10.0.0.0".parse().unwrap(), 8)),
+		);
+		assert_eq!(
+			"[2001:db8::1]:22".parse::<Destination>().map(|destination| destination.host),
+			Ok(Host::Network("2001:db8::1".parse().unwrap(), 128)),
+		);
+		assert_eq!(
+			"[2001:db8::/32]:22".parse::<Destination>().map(|destination| destination.host),
+			Ok(Host::Network("2001:db8::".parse().unwrap(), 32)),
+		);
+
+		for bad in &[ "db.internal", "db.internal:", "[::1:22", "10.0.0.0/33:80", "[::/129]:80", ":80", "host/8:80", "host:80-" ] {
+			assert!(bad.parse::<Destination>().is_err(), "{}", bad);
+		}
+	}
+
+	#[test]
+	fn names() {
+		assert!(permits(&[ "db.internal:5432" ], "DB.Internal.", "10.1.2.3:5432"));
+		assert!(!permits(&[ "db.internal:5432" ], "db.internal", "10.1.2.3:5433"));
+		assert!(!permits(&[ "db.internal:5432" ], "other.internal", "10.1.2.3:5432"));
+
+		assert!(permits(&[ "*.example.com:80,443" ], "api.example.com", "192.0.2.1:443"));
+		assert!(permits(&[ "*.example.com:80,443" ], "a.b.example.com", "192.0.2.1:80"));
+		assert!(!permits(&[ "*.example.com:80,443" ], "example.com", "192.0.2.1:80"));
+		assert!(!permits(&[ "*.example.com:80,443" ], "badexample.com", "192.0.2.1:80"));
+
+		assert!(permits(&[ "*:22" ], "anything", "[2001:db8::1]:22"));
+		assert!(!permits(&[ "*:22" ], "anything", "192.0.2.1:23"));
+		assert!(!permits(&[], "anything", "192.0.2.1:22"));
+	}
+
+	#[test]
+	fn addresses() {
+		assert!(permits(&[ "192.0.2.1:80" ], "example.com", "192.0.2.1:80"));
+		assert!(!permits(&[ "192.0.2.1:80" ], "example.com", "192.0.2.2:80"));
+
+		assert!(permits(&[ "10.0.0.0/8:1000-2000" ], "db", "10.255.0.1:1000"));
+		assert!(permits(&[ "10.0.0.0/8:1000-2000" ], "db", "10.0.0.1:2000"));
+		assert!(!permits(&[ "10.0.0.0/8:1000-2000" ], "db", "10.0.0.1:2001"));
+		assert!(!permits(&[ "10.0.0.0/8:1000-2000" ], "db", "11.0.0.1:1500"));
+		assert!(permits(&[ "0.0.0.0/0:*" ], "db", "203.0.113.9:1"));
+
+		assert!(permits(&[ "[2001:db8::/32]:443" ], "v6", "[2001:db8:ffff::1]:443"));
+		assert!(!permits(&[ "[2001:db8::/32]:443" ], "v6", "[2001:db9::1]:443"));
+		assert!(!permits(&[ "[2001:db8::/32]:443" ], "v6", "192.0.2.1:443"));
+	}
+
+	#[test]
+	fn mapped_addresses() {
+		// an IPv4 address in IPv6 clothing is still that address
+		assert!(permits(&[ "10.0.0.0/8:80" ], "db", "[::ffff:10.1.2.3]:80"));
+		assert!(!permits(&[ "10.0.0.0/8:80" ], "db", "[::ffff:11.1.2.3]:80"));
+		assert!(!permits(&[ "[::/0]:80" ], "db", "[::ffff:10.1.2.3]:80"));
+	}
+}
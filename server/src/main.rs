@@ -1,22 +1,28 @@
 #[macro_use] extern crate clap;
 #[macro_use] extern crate log;
 
+mod auth;
 mod proxy;
 mod websocket;
 mod netstat;
 mod config;
+mod destination;
 mod port;
 
-use std::{ env, thread };
+use std::env;
+use std::future;
 use std::io::{ self, Write };
 use std::process::exit;
 use std::str::FromStr;
-use std::sync::mpsc;
+use std::sync::Arc;
 
 use log::LevelFilter as LogLevelFilter;
 
 use simple_logger::SimpleLogger;
 
+use tokio::runtime::Runtime;
+use tokio::sync::oneshot;
+
 const PROXY_PORT: u16 = 3321u16;
 const SERVER_PORT: u16 = 3322u16;
 
@@ -57,58 +63,127 @@ fn main() {
 	let key_var = matches.value_of("KEY")
 		.unwrap_or("KEY");
 	let config_file = matches.value_of("CONFIG")
-		.map(|s| s.to_string())
-		.or(
-			env::var("HOME").ok()
-				.zip(env::var("REPL_SLUG").ok())
-				.map(|(home, slug)| format!("{}/{}/.replit", home, slug))
-		);
+		.map(|s| s.to_string());
+	let default_file = env::var("HOME").ok()
+		.zip(env::var("REPL_SLUG").ok())
+		.map(|(home, slug)| format!("{}/{}/.replit", home, slug));
 	let port = matches.value_of("PORT");
 
-	if let Ok(key) = env::var(key_var) {
+	let config = match config_file.as_ref().or(default_file.as_ref()) {
+		Some(file) => match config::load_config(file) {
+			Ok(config) => config,
+			// only a repl has a .replit of its own
+			Err(error) if config_file.is_none() && error.kind() == io::ErrorKind::NotFound => {
+				info!("no config at {}, using the defaults", file);
+				config::Config::default()
+			},
+			Err(error) => {
+				error!("failed to load config from {}: {}", file, error);
+				exit(1);
+			},
+		},
+		None => config::Config::default(),
+	};
+	let key = env::var(key_var).ok();
+
+	if key.is_some() || config.authorized_keys.is_some() {
 		info!("running as server");
 
-		let port = if let Some(port_str) = port {
+		// ports detected automatically are kept up to date as listeners change
+		let (port, watch) = if let Some(port_str) = port {
 			if let Ok(port) = u16::from_str(port_str) {
-				Some(port)
+				(Some(port), None)
 			} else {
 				error!("port argument invalid");
 				exit(1);
 			}
+		} else if let Some(port) = config.port {
+			(Some(port), None)
 		} else {
-			config_file
-				.and_then(|config_file| config::load_config(config_file.as_str()))
-				.and_then(|config| config.port)
-				.or_else(|| port::get_port_auto())
+			let preferences = config.port_selection.clone()
+				.unwrap_or_default()
+				.to_preferences()
+				.unwrap_or_else(|error| {
+					error!("invalid port selection in config: {}", error);
+					exit(1);
+				});
+
+			(port::get_port_auto(&preferences), Some(preferences))
 		};
 
 		if let Some(port) = port {
 			info!("starting intercepting port {}", port);
-		} else {
-			warn!("no port detected, not proxying");
 		}
 
-		let (proxy_shutdown, proxy_signal) = mpsc::channel();
-		let (server_shutdown, server_signal) = mpsc::channel();
-		
-		thread::spawn(move || proxy::start(port, proxy_signal));
-		thread::spawn(move || websocket::start(key.as_str(), server_signal));
+		let router = proxy::Router::new(config.routes.clone(), port, config.forwarding)
+			.unwrap_or_else(|error| {
+				error!("invalid route in config: {}", error);
+				exit(1);
+			});
+		let router = Arc::new(router);
+
+		if router.is_empty() {
+			if watch.is_some() {
+				warn!("no port detected or routes configured, waiting for a listener");
+			} else {
+				warn!("no port detected or routes configured, not proxying");
+			}
+		}
+
+		let runtime = Runtime::new().unwrap_or_else(|error| {
+			error!("failed to start runtime: {}", error);
+			exit(1);
+		});
+
+		let (proxy_shutdown, proxy_signal) = oneshot::channel();
+		let (server_shutdown, server_signal) = oneshot::channel();
+
+		if let Some(preferences) = watch {
+			runtime.spawn(port::watch(router.clone(), preferences));
+		}
+		runtime.spawn(proxy::start(router, proxy_signal));
+		let key_permissions = match config.permissions {
+			Some(ref permissions) => permissions.to_permissions().unwrap_or_else(|error| {
+				error!("invalid permissions in config: {}", error);
+				exit(1);
+			}),
+			None => auth::Permissions::default(),
+		};
+
+		let authenticator = Arc::new(auth::Authenticator::new(
+			key,
+			key_permissions,
+			config.authorized_keys,
+		));
+		let destinations = destination::Destinations::new(&config.destinations)
+			.unwrap_or_else(|error| {
+				error!("invalid destination in config: {}", error);
+				exit(1);
+			});
+		runtime.spawn(websocket::start(
+			authenticator,
+			Arc::new(destinations),
+			Arc::new(config.shell),
+			server_signal,
+		));
 
 		println!("Press <ENTER> to exit");
 
 		let stdin = io::stdin();
 		let mut stdout = io::stdout();
 		loop {
-			let _ = stdin.read_line(&mut String::new());
+			// without a terminal there's nobody to ask, so run until killed
+			if !matches!(stdin.read_line(&mut String::new()), Ok(read) if read > 0) {
+				info!("stdin closed, running until killed");
+				runtime.block_on(future::pending::<()>());
+			}
 
 			print!("Really exit? [y/N] ");
 			let _ = stdout.flush();
 
 			let mut buffer = String::new();
-			if stdin.read_line(&mut buffer).is_ok() {
-				if buffer.trim().to_lowercase() == "y" {
-					break
-				}
+			if stdin.read_line(&mut buffer).is_ok() && buffer.trim().to_lowercase() == "y" {
+				break
 			}
 		}
 
@@ -117,9 +192,11 @@ fn main() {
 		if proxy_shutdown.send(()).is_err() { error!("failed to shut down proxy") }
 		if server_shutdown.send(()).is_err() { error!("failed to shut down server") }
 
+		runtime.shutdown_background();
+
 		info!("goodbye");
 	} else {
-		error!("{} environment variable not set", key_var);
+		error!("{} environment variable not set and no authorized keys configured", key_var);
 		exit(1);
 	}
 }
@@ -1,49 +1,351 @@
-mod netstat;
 mod parse;
 mod parse_ip;
 mod process;
 
+pub use process::ProcessIndex;
+
+use std::fs;
 use std::net;
+use std::path::Path;
 
-const TCP_TAB_PATH: &str = "/proc/net/tcp";
-const TCP6_TAB_PATH: &str = "/proc/net/tcp6";
-const UDP_TAB_PATH: &str = "/proc/net/udp";
-const UDP6_TAB_PATH: &str = "/proc/net/udp6";
+const PROC_DIR: &str = "/proc";
 
-#[derive(Clone, Debug)]
+/// A socket's state, numbered as in the kernel's `include/net/tcp_states.h`.
+///
+/// UDP sockets use these too: `Established` once connected to a peer, and
+/// `Close` otherwise.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TcpState {
+	Established = 1,
+	SynSent = 2,
+	SynRecv = 3,
+	FinWait1 = 4,
+	FinWait2 = 5,
+	TimeWait = 6,
+	Close = 7,
+	CloseWait = 8,
+	LastAck = 9,
+	Listen = 10,
+	Closing = 11,
+	NewSynRecv = 12,
+	BoundInactive = 13,
+}
+
+impl TcpState {
+	pub fn from_u8(state: u8) -> Option<Self> {
+		use TcpState::*;
+
+		Some(match state {
+			1 => Established,
+			2 => SynSent,
+			3 => SynRecv,
+			4 => FinWait1,
+			5 => FinWait2,
+			6 => TimeWait,
+			7 => Close,
+			8 => CloseWait,
+			9 => LastAck,
+			10 => Listen,
+			11 => Closing,
+			12 => NewSynRecv,
+			13 => BoundInactive,
+			_ => return None,
+		})
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+	Tcp,
+	Udp,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Process {
 	pub pid: i32,
+	/// The name from `stat`, which the kernel truncates to 15 bytes.
 	pub name: String,
+	/// The full command line, empty for kernel threads.
+	pub cmdline: Vec<String>,
 }
 
 #[derive(Clone, Debug)]
 pub struct SockTabEntry {
-	pub ino: String,
+	pub sock_type: SockType,
 	pub local_addr: net::SocketAddr,
 	pub remote_addr: net::SocketAddr,
-	pub state: u8,
+	pub state: TcpState,
 	pub uid: u32,
 	pub process: Process,
 }
 
-#[allow(dead_code)]
-#[derive(Clone, Copy, Debug, PartialEq)]
+impl SockTabEntry {
+	pub fn protocol(&self) -> Protocol {
+		self.sock_type.protocol()
+	}
+
+	/// Whether others can reach this socket: a TCP socket accepting
+	/// connections, or a UDP socket bound to a port but not connected.
+	pub fn is_listening(&self) -> bool {
+		match self.protocol() {
+			Protocol::Tcp => self.state == TcpState::Listen,
+			Protocol::Udp => self.state == TcpState::Close
+				&& self.local_addr.port() != 0
+				&& self.remote_addr.port() == 0,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum SockType {
-	TCP,
-	TCP6,
-	UDP,
-	UDP6,
+	Tcp,
+	Tcp6,
+	Udp,
+	Udp6,
+}
+
+impl SockType {
+	const ALL: [SockType; 4] = [ SockType::Tcp, SockType::Tcp6, SockType::Udp, SockType::Udp6 ];
+
+	pub fn protocol(self) -> Protocol {
+		match self {
+			SockType::Tcp | SockType::Tcp6 => Protocol::Tcp,
+			SockType::Udp | SockType::Udp6 => Protocol::Udp,
+		}
+	}
+
+	// relative to the proc directory
+	fn tab_path(self) -> &'static str {
+		match self {
+			SockType::Tcp => "net/tcp",
+			SockType::Tcp6 => "net/tcp6",
+			SockType::Udp => "net/udp",
+			SockType::Udp6 => "net/udp6",
+		}
+	}
 }
 
-pub fn os_socks(sock_type: SockType) -> Vec<SockTabEntry> {
-	let tab_path = match sock_type {
-		SockType::TCP => TCP_TAB_PATH,
-		SockType::TCP6 => TCP6_TAB_PATH,
-		SockType::UDP => UDP_TAB_PATH,
-		SockType::UDP6 => UDP6_TAB_PATH,
-	};
+/// Which sockets `query` returns. Fields left unset match any socket.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+	pub protocol: Option<Protocol>,
+	pub state: Option<TcpState>,
+	pub uid: Option<u32>,
+	/// Only sockets others can reach; see `SockTabEntry::is_listening`.
+	pub listening: bool,
+}
+
+impl Filter {
+	/// Every socket listening over `protocol`, or over either if `None`.
+	pub fn listening(protocol: Option<Protocol>) -> Self {
+		Self { protocol, listening: true, ..Self::default() }
+	}
+
+	pub fn matches(&self, sock: &SockTabEntry) -> bool {
+		self.protocol.is_none_or(|protocol| sock.protocol() == protocol)
+			&& self.state.is_none_or(|state| sock.state == state)
+			&& self.uid.is_none_or(|uid| sock.uid == uid)
+			&& (!self.listening || sock.is_listening())
+	}
+}
+
+/// The sockets matching `filter` that belong to processes we can see, over
+/// IPv4 and IPv6.
+pub fn query(filter: &Filter) -> Vec<SockTabEntry> {
+	query_in(Path::new(PROC_DIR), filter)
+}
+
+fn query_in(proc_dir: &Path, filter: &Filter) -> Vec<SockTabEntry> {
+	// only read the tables the filter could match
+	let sock_types: Vec<SockType> = SockType::ALL.iter()
+		.copied()
+		.filter(|sock_type| filter.protocol.is_none_or(|protocol| sock_type.protocol() == protocol))
+		.collect();
+
+	let mut socks = read_socks(proc_dir, &sock_types);
+	socks.retain(|sock| filter.matches(sock));
+
+	socks
+}
+
+fn read_socks(proc_dir: &Path, sock_types: &[SockType]) -> Vec<SockTabEntry> {
+	debug!("doing netstat");
+
+	let processes = ProcessIndex::build(proc_dir);
+
+	sock_types.iter()
+		.flat_map(|sock_type| {
+			let tab_path = proc_dir.join(sock_type.tab_path());
+			trace!("tab path is {}", tab_path.display());
+
+			match fs::read_to_string(&tab_path) {
+				Ok(tab_data) => parse::parse_sock_tab(&tab_data, *sock_type, &processes),
+				Err(_) => {
+					warn!("failed to read sock tab");
+					vec![]
+				},
+			}
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::os::unix::fs::symlink;
+	use std::path::PathBuf;
+
+	const TCP_TAB: &str = "\
+  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:0BB8 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 1001 1 0000000000000000 100 0 0 10 0
+   1: 00000000:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1002 1 0000000000000000 100 0 0 10 0
+   2: 0100007F:D431 0100007F:0BB8 01 00000000:00000000 00:00000000 00000000  1000        0 1004 1 0000000000000000 20 4 30 10 -1
+   3: 0100007F:2382 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 9999 1 0000000000000000 100 0 0 10 0
+";
+
+	const TCP6_TAB: &str = "\
+  sl  local_address                         remote_address                        st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000000000000000000001000000:1538 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000   999        0 1003 1 0000000000000000 100 0 0 10 0
+";
+
+	const UDP_TAB: &str = "\
+   sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops
+  123: 00000000:0035 00000000:0000 07 00000000:00000000 00:00000000 00000000   101        0 2001 2 0000000000000000 0
+  200: 0F02000A:C8D5 08080808:0035 01 00000000:00000000 00:00000000 00000000  1000        0 2002 2 0000000000000000 0
+";
+
+	const UDP6_TAB: &str = "\
+  sl  local_address                         remote_address                        st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops
+  301: 00000000000000000000000000000000:6987 00000000000000000000000000000000:0000 07 00000000:00000000 00:00000000 00000000  1000        0 2003 2 0000000000000000 0
+";
+
+	/// A fake `/proc`, removed again when dropped.
+	struct Fixture(PathBuf);
+
+	impl Fixture {
+		fn new(name: &str) -> Self {
+			let root = std::env::temp_dir()
+				.join(format!("autobahn-netstat-{}-{}", std::process::id(), name));
+			let _ = fs::remove_dir_all(&root);
+
+			fs::create_dir_all(root.join("net")).unwrap();
+			fs::write(root.join("net/tcp"), TCP_TAB).unwrap();
+			fs::write(root.join("net/tcp6"), TCP6_TAB).unwrap();
+			fs::write(root.join("net/udp"), UDP_TAB).unwrap();
+			fs::write(root.join("net/udp6"), UDP6_TAB).unwrap();
+
+			let fixture = Self(root);
+			fixture.process(100, "node server", b"node\0server.js\0", &[ "socket:[1001]", "/dev/null", "socket:[2002]" ]);
+			fixture.process(200, "python3", b"python3\0-m\0http.server\0", &[ "socket:[1002]", "socket:[1003]" ]);
+			// a child sharing its parent's socket, and one that's connected
+			fixture.process(201, "python3", b"python3\0-m\0http.server\0", &[ "socket:[1002]", "socket:[1004]" ]);
+			fixture.process(300, "kworker/0:1", b"", &[]);
+			fixture.process(500, "dnsmasq", b"dnsmasq\0-k\0", &[ "socket:[2001]" ]);
+			fixture.process(600, "srcds_linux", b"./srcds_linux\0-game\0tf\0", &[ "socket:[2003]" ]);
+			// another user's process, whose fds can't be read
+			fs::create_dir_all(fixture.0.join("400")).unwrap();
+			fs::write(fixture.0.join("400/stat"), "400 (postgres) S 1").unwrap();
+
+			fixture
+		}
+
+		fn process(&self, pid: i32, name: &str, cmdline: &[u8], links: &[&str]) {
+			let base = self.0.join(pid.to_string());
+			fs::create_dir_all(base.join("fd")).unwrap();
+			fs::write(base.join("stat"), format!("{} ({}) S 1 {} {} 0 -1", pid, name, pid, pid)).unwrap();
+			fs::write(base.join("cmdline"), cmdline).unwrap();
+
+			for (fd, target) in links.iter().enumerate() {
+				symlink(target, base.join("fd").join(fd.to_string())).unwrap();
+			}
+		}
+	}
+
+	impl Drop for Fixture {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.0);
+		}
+	}
+
+	#[test]
+	fn index() {
+		let fixture = Fixture::new("index");
+		let index = ProcessIndex::build(&fixture.0);
+
+		assert_eq!(index.get(1001), Some(&Process {
+			pid: 100,
+			name: "node server".into(),
+			cmdline: vec![ "node".into(), "server.js".into() ],
+		}));
+		assert_eq!(index.get(1002).map(|process| process.pid), Some(200));
+		assert_eq!(index.get(1003).map(|process| process.pid), Some(200));
+		assert_eq!(index.get(1004).map(|process| process.pid), Some(201));
+		assert_eq!(index.get(9999), None);
+	}
+
+	#[test]
+	fn sock_tabs() {
+		let fixture = Fixture::new("sock-tabs");
+		let socks = read_socks(&fixture.0, &SockType::ALL);
+
+		let summary: Vec<(SockType, String, String, TcpState, u32, i32)> = socks.iter()
+			.map(|sock| (
+				sock.sock_type,
+				sock.local_addr.to_string(),
+				sock.remote_addr.to_string(),
+				sock.state,
+				sock.uid,
+				sock.process.pid,
+			))
+			.collect();
+
+		// the socket nobody visible owns is left out
+		assert_eq!(summary, vec![
+			(SockType::Tcp, "127.0.0.1:3000".into(), "0.0.0.0:0".into(), TcpState::Listen, 1000, 100),
+			(SockType::Tcp, "0.0.0.0:8080".into(), "0.0.0.0:0".into(), TcpState::Listen, 0, 200),
+			(SockType::Tcp, "127.0.0.1:54321".into(), "127.0.0.1:3000".into(), TcpState::Established, 1000, 201),
+			(SockType::Tcp6, "[::1]:5432".into(), "[::]:0".into(), TcpState::Listen, 999, 200),
+			(SockType::Udp, "0.0.0.0:53".into(), "0.0.0.0:0".into(), TcpState::Close, 101, 500),
+			(SockType::Udp, "10.0.2.15:51413".into(), "8.8.8.8:53".into(), TcpState::Established, 1000, 100),
+			(SockType::Udp6, "[::]:27015".into(), "[::]:0".into(), TcpState::Close, 1000, 600),
+		]);
+		assert_eq!(socks[1].process.cmdline, [ "python3", "-m", "http.server" ]);
+	}
+
+	#[test]
+	fn filters() {
+		let fixture = Fixture::new("filters");
+		let ports = |filter: Filter| -> Vec<u16> {
+			query_in(&fixture.0, &filter).iter()
+				.map(|sock| sock.local_addr.port())
+				.collect()
+		};
 
-	trace!("tab path is {}", tab_path);
+		assert_eq!(ports(Filter::listening(None)), [ 3000, 8080, 5432, 53, 27015 ]);
+		assert_eq!(ports(Filter::listening(Some(Protocol::Tcp))), [ 3000, 8080, 5432 ]);
+		assert_eq!(ports(Filter::listening(Some(Protocol::Udp))), [ 53, 27015 ]);
+		assert_eq!(
+			ports(Filter { uid: Some(1000), ..Filter::listening(None) }),
+			[ 3000, 27015 ],
+		);
+		assert_eq!(
+			ports(Filter { state: Some(TcpState::Established), ..Filter::default() }),
+			[ 54321, 51413 ],
+		);
+		assert_eq!(
+			ports(Filter {
+				protocol: Some(Protocol::Udp),
+				state: Some(TcpState::Established),
+				..Filter::default()
+			}),
+			[ 51413 ],
+		);
+	}
 
-	netstat::do_netstat(tab_path)
+	#[test]
+	fn missing_proc_dir() {
+		let root = std::env::temp_dir().join("autobahn-netstat-missing");
+		assert!(query_in(&root, &Filter::default()).is_empty());
+	}
 }
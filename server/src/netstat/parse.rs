@@ -1,9 +1,9 @@
-use super::{ parse_ip, process, Process, SockTabEntry };
+use super::{ parse_ip, Process, ProcessIndex, SockTabEntry, SockType, TcpState };
 
 use std::net;
 use std::str::FromStr;
 
-pub fn parse_sock_tab(sock_tab: String) -> Vec<SockTabEntry> {
+pub fn parse_sock_tab(sock_tab: &str, sock_type: SockType, processes: &ProcessIndex) -> Vec<SockTabEntry> {
 	let mut out: Vec<SockTabEntry> = vec![];
 
 	let mut lines = sock_tab.split('\n');
@@ -13,6 +13,10 @@ pub fn parse_sock_tab(sock_tab: String) -> Vec<SockTabEntry> {
 		// ignore comments
 		let line = line.get(0..line.find('#').unwrap_or(line.len())).unwrap();
 
+		if line.trim().is_empty() {
+			continue;
+		}
+
 		trace!("parsing sock tab line {}", line);
 
 		let fields: Vec<&str> = line.split_whitespace().collect();
@@ -25,7 +29,7 @@ pub fn parse_sock_tab(sock_tab: String) -> Vec<SockTabEntry> {
 
 		let local_addr: Option<net::SocketAddr>;
 		let remote_addr: Option<net::SocketAddr>;
-		let state: Option<u8>;
+		let state: Option<TcpState>;
 		let uid: Option<u32>;
 		let process: Option<Process>;
 
@@ -43,7 +47,7 @@ pub fn parse_sock_tab(sock_tab: String) -> Vec<SockTabEntry> {
 			continue;
 		}
 
-		if let Ok(sstate) = u8::from_str_radix(fields[3], 16) {
+		if let Some(sstate) = u8::from_str_radix(fields[3], 16).ok().and_then(TcpState::from_u8) {
 			state = Some(sstate);
 		} else {
 			warn!("couldn't parse state");
@@ -57,16 +61,17 @@ pub fn parse_sock_tab(sock_tab: String) -> Vec<SockTabEntry> {
 			continue;
 		}
 
-		if let Ok(sproc) = process::get_info(fields[9].to_string()) {
-			process = Some(sproc);
+		if let Some(sproc) = u64::from_str(fields[9]).ok().and_then(|ino| processes.get(ino)) {
+			process = Some(sproc.clone());
 		} else {
-			warn!("couldn't get process info");
+			// sockets of other users' processes, or ones that just closed
+			debug!("couldn't get process info");
 			continue;
 		}
 
 		out.push(
 			SockTabEntry {
-				ino: fields[9].to_string(),
+				sock_type,
 				local_addr: local_addr.unwrap(),
 				remote_addr: remote_addr.unwrap(),
 				state: state.unwrap(),
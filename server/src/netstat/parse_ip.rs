@@ -1,7 +1,12 @@
-use std::net::{ SocketAddr, Ipv6Addr, SocketAddrV6 };
+use std::net::{ Ipv4Addr, Ipv6Addr, SocketAddr };
 
+// the kernel prints addresses as 32-bit words in host byte order
 pub fn parse(ip: &str) -> Result<SocketAddr, ()> {
-	parse_v4(ip).or_else(|_| parse_v6(ip))
+	if ip.len() == 37 {
+		parse_v6(ip)
+	} else {
+		parse_v4(ip)
+	}
 }
 
 pub fn parse_v4(sock: &str) -> Result<SocketAddr, ()> {
@@ -10,32 +15,10 @@ pub fn parse_v4(sock: &str) -> Result<SocketAddr, ()> {
 		return Err(());
 	}
 
-	let split = sock.find(':');
-	if split.is_none() {
-		warn!("failed to parse socket without port");
-		return Err(());
-	}
-
-	let (ip, port) = sock.split_at(split.unwrap());
-
-	if let Ok(ip_val) = u64::from_str_radix(ip, 16) {
-		let d = ip_val % 256;
-		let c = (ip_val / 256) % 256;
-		let b = (ip_val / 256 / 256) % 256;
-		let a = (ip_val / 256 / 256 / 256) % 256;
+	let (ip, port) = split(sock)?;
+	let ip = parse_word(ip)?;
 
-		if let Ok(port_val) = u16::from_str_radix(port.get(1..).unwrap(), 16) {
-			let sock_str = format!("{}.{}.{}.{}:{}", a, b, c, d, port_val);
-			sock_str.as_str().parse()
-				.map_err(|_| warn!("failed to parse socket {}", sock_str))
-		} else {
-			warn!("failed to parse port {}", port);
-			Err(())
-		}
-	} else {
-		warn!("failed to parse ip {}", ip);
-		Err(())
-	}
+	Ok(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
 }
 
 pub fn parse_v6(sock: &str) -> Result<SocketAddr, ()> {
@@ -44,50 +27,62 @@ pub fn parse_v6(sock: &str) -> Result<SocketAddr, ()> {
 		return Err(());
 	}
 
-	let split = sock.find(':');
-	if split.is_none() {
-		warn!("failed to parse socket without port");
-		return Err(());
+	let (ip, port) = split(sock)?;
+	let mut octets = [ 0; 16 ];
+	for (index, chunk) in octets.chunks_mut(4).enumerate() {
+		chunk.copy_from_slice(&parse_word(&ip[index * 8..(index + 1) * 8])?);
 	}
 
-	let (ip, port) = sock.split_at(split.unwrap());
-	
-	if let Ok(mut ip_val) = u128::from_str_radix(ip, 16) {
-		let h = ip_val % 256;
-		ip_val /= 256;
-		let g = ip_val % 256;
-		ip_val /= 256;
-		let f = ip_val % 256;
-		ip_val /= 256;
-		let e = ip_val % 256;
-		ip_val /= 256;
-		let d = ip_val % 256;
-		ip_val /= 256;
-		let c = ip_val % 256;
-		ip_val /= 256;
-		let b = ip_val % 256;
-		ip_val /= 256;
-		let a = ip_val % 256;
+	Ok(SocketAddr::new(Ipv6Addr::from(octets).into(), port))
+}
+
+fn split(sock: &str) -> Result<(&str, u16), ()> {
+	let (ip, port) = sock.split_once(':').ok_or_else(|| warn!("failed to parse socket without port"))?;
+	let port = u16::from_str_radix(port, 16).map_err(|_| warn!("failed to parse port {}", port))?;
 
-		let ip_addr = Ipv6Addr::new(
-			a as u16,
-			b as u16,
-			c as u16,
-			d as u16,
-			e as u16,
-			f as u16,
-			g as u16,
-			h as u16,
+	Ok((ip, port))
+}
+
+fn parse_word(word: &str) -> Result<[u8; 4], ()> {
+	u32::from_str_radix(word, 16)
+		.map(u32::to_ne_bytes)
+		.map_err(|_| warn!("failed to parse ip {}", word))
+}
+
+// the layouts below are how a little-endian machine like x86 or ARM prints them
+#[cfg(all(test, target_endian = "little"))]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn v4() {
+		// each word used to be read most significant byte first, giving 1.0.0.127
+		assert_eq!(parse("0100007F:0BB8"), Ok("127.0.0.1:3000".parse().unwrap()));
+		assert_eq!(parse("00000000:1F90"), Ok("0.0.0.0:8080".parse().unwrap()));
+		assert_eq!(parse("0F02000A:01BB"), Ok("10.0.2.15:443".parse().unwrap()));
+	}
+
+	#[test]
+	fn v6() {
+		assert_eq!(
+			parse("00000000000000000000000001000000:0BB8"),
+			Ok("[::1]:3000".parse().unwrap()),
+		);
+		assert_eq!(
+			parse("B80D0120000000000000000001000000:0050"),
+			Ok("[2001:db8::1]:80".parse().unwrap()),
+		);
+		assert_eq!(
+			parse("0000000000000000FFFF00000100007F:1F90"),
+			Ok("[::ffff:127.0.0.1]:8080".parse().unwrap()),
 		);
+	}
 
-		if let Ok(port_val) = u16::from_str_radix(port.get(1..).unwrap(), 16) {
-			Ok(SocketAddr::V6(SocketAddrV6::new(ip_addr, port_val, 0, 0)))
-		} else {
-			warn!("failed to parse port {}", port);
-			Err(())
+	#[test]
+	fn malformed() {
+		for bad in &[ "", "0100007F", "0100007F0BB8", "0100007G:0BB8", "0100007F:GBB8", "100007F:0BB8" ] {
+			assert_eq!(parse(bad), Err(()), "{}", bad);
 		}
-	} else {
-		warn!("failed to parse ip {}", ip);
-		Err(())
+		assert_eq!(parse("0000000000000000000000000100000:0BB8"), Err(()));
 	}
 }
@@ -1,105 +1,102 @@
 use super::Process;
 
+use std::collections::HashMap;
 use std::fs;
-use std::str::FromStr;
+use std::path::Path;
 
-const PROC_DIR: &str = "/proc";
-
-#[derive(Clone, Debug)]
-struct ProcFd {
-	pub inode: String,
-	pub base: String,
-	pub pid: i32,
+/// Which process owns each socket inode, from a single pass over `/proc`.
+#[derive(Clone, Debug, Default)]
+pub struct ProcessIndex {
+	sockets: HashMap<u64, Process>,
 }
 
-pub fn get_info(inode: String) -> Result<Process, ()> {
-	fs::read_dir(PROC_DIR).map_err(|_| ())
-		.and_then(|dir| {
-			for _file in dir {
-				if let Ok(file) = _file {
-					if let Ok(file_type) = file.file_type() {
-						if !file_type.is_dir() {
-							continue;
-						}
-
-						let file_name = file.file_name();
-						let file_name = file_name.to_str();
-						if file_name.is_none() { continue; }
-						let file_name = file_name.unwrap();
-
-						trace!("processing {}", file_name);
-
-						if let Ok(pid) = i32::from_str(file_name) {
-							let ctx = ProcFd {
-								inode: inode.clone(),
-								base: format!("{}/{}", PROC_DIR, file_name),
-								pid: pid,
-							};
-
-							if let Some(process) = process_proc_dir(ctx) {
-								return Ok(process);
-							}
-						}
-					}
-				}
+impl ProcessIndex {
+	pub fn build(proc_dir: &Path) -> Self {
+		let mut sockets = HashMap::new();
+
+		let dir = match fs::read_dir(proc_dir) {
+			Ok(dir) => dir,
+			Err(err) => {
+				warn!("failed to read {}: {}", proc_dir.display(), err);
+				return Self { sockets };
+			},
+		};
+
+		let mut pids: Vec<i32> = dir.flatten()
+			.filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+			.collect();
+		// a socket shared after a fork goes to the parent, which usually has the lower pid
+		pids.sort_unstable();
+
+		for pid in pids {
+			let base = proc_dir.join(pid.to_string());
+
+			// other users' processes can't be looked into
+			let fds = match fs::read_dir(base.join("fd")) {
+				Ok(fds) => fds,
+				Err(_) => {
+					trace!("couldn't read fds of {}", pid);
+					continue;
+				},
+			};
+
+			let inodes: Vec<u64> = fds.flatten()
+				.filter_map(|fd| fs::read_link(fd.path()).ok())
+				.filter_map(|target| socket_inode(&target))
+				.collect();
+
+			if inodes.is_empty() {
+				continue;
 			}
 
-			trace!("ran out of proc files");
-			Err(())
-		})
-}
+			let process = match read_process(&base, pid) {
+				Some(process) => process,
+				None => {
+					debug!("failed to read stat of {}", pid);
+					continue;
+				},
+			};
 
-fn process_proc_dir(ctx: ProcFd) -> Option<Process> {
-	let fd_dir = format!("{}/{}", ctx.base, "fd");
-
-	if let Ok(dir) = fs::read_dir(fd_dir.clone()) {
-		for _file in dir {
-			if let Ok(file) = _file {
-				if let Ok(link_target) = fs::read_link(
-					format!("{}/{}", fd_dir, file.file_name().to_str().unwrap())
-				) {
-					trace!("processing link target {:?}", link_target.to_str());
-
-					let link_target_string = match link_target.to_str() {
-						Some(string) => string,
-						_ => continue,
-					};
-
-					if format!("socket:[{}]", ctx.inode) != link_target_string {
-						trace!("socket didn't match {}", ctx.inode);
-						continue;
-					}
-
-					if let Ok(stat_data) = fs::read(format!("{}/{}", ctx.base, "stat"))
-						.map_err(|_| ())
-						.and_then(|data| String::from_utf8(data).map_err(|_| ()))
-					{
-						let parts: Vec<&str> = stat_data.split_whitespace().collect();
-						let proc_name = get_proc_name(parts[1].to_string());
-
-						return Some(
-							Process {
-								pid: ctx.pid,
-								name: proc_name,
-							}
-						);
-					} else {
-						warn!("failed to read stat file");
-					}
-				} else {
-					warn!("couldn't follow symlink");
-				}
+			for inode in inodes {
+				sockets.entry(inode).or_insert_with(|| process.clone());
 			}
 		}
 
-		trace!("ran out of files");
-		None
-	} else {
-		trace!("directory read nulled");
-		None
+		Self { sockets }
+	}
+
+	pub fn get(&self, inode: u64) -> Option<&Process> {
+		self.sockets.get(&inode)
 	}
 }
 
-fn get_proc_name(string: String) -> String {
-	string.get((string.find('(').unwrap_or(0) + 1)..string.rfind(')').unwrap_or(0)).unwrap().into()
+// fd links to sockets read `socket:[<inode>]`
+fn socket_inode(target: &Path) -> Option<u64> {
+	target.to_str()?
+		.strip_prefix("socket:[")?
+		.strip_suffix(']')?
+		.parse().ok()
+}
+
+fn read_process(base: &Path, pid: i32) -> Option<Process> {
+	let stat = fs::read_to_string(base.join("stat")).ok()?;
+	// the name is in parentheses and may itself contain spaces or parentheses
+	let name = stat.get(stat.find('(')? + 1..stat.rfind(')')?)?.to_string();
+
+	// arguments are NUL-terminated, except where a process rewrote them;
+	// kernel threads have none
+	let cmdline = fs::read(base.join("cmdline"))
+		.map(|data| {
+			let args = data.strip_suffix(&[ 0 ]).unwrap_or(&data);
+			if args.is_empty() {
+				return vec![];
+			}
+
+			args.split(|byte| *byte == 0)
+				.map(|arg| String::from_utf8_lossy(arg).into_owned())
+				.collect()
+		})
+		.unwrap_or_default();
+
+	Some(Process { pid, name, cmdline })
 }
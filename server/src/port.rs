@@ -1,73 +1,264 @@
-use crate::netstat;
-
-/* impl fmt::Debug for netstat::SockTabEntry {
-	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		f.debug_struct("SockTabEntry")
-			.field("ino", &self.ino)
-			.field("local_addr", &self.local_addr)
-			.field("remote_addr", &self.remote_addr)
-			.field("state", &self.state)
-			.field("uid", &self.uid)
-			.field("process.name", &self.process.name)
-			.field("process.pid", &self.process.pid)
-			.finish()
+use crate::{ PROXY_PORT, SERVER_PORT };
+use crate::auth::PortSet;
+use crate::netstat::{ self, Filter, Protocol, SockTabEntry };
+use crate::proxy::Router;
+
+use std::collections::BTreeMap;
+use std::io::{ self, Write };
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::task;
+use tokio::time::{ self, MissedTickBehavior };
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How to choose between several listeners. Each preference narrows down the
+/// candidates, unless nothing matches it; if that still leaves more than one,
+/// either the user is asked or the lowest port is taken.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Preferences {
+	pub process: Option<String>,
+	pub ports: Option<PortSet>,
+	pub ask: bool,
+}
+
+pub fn get_port_auto(preferences: &Preferences) -> Option<u16> {
+	let listeners = preferred(listeners(), preferences);
+	let lowest = listeners.first().map(|listener| listener.local_addr.port());
+
+	match listeners.len() {
+		0 | 1 => lowest,
+		_ if preferences.ask => ask(&listeners).or(lowest),
+		count => {
+			info!("{} listeners detected, using the lowest port", count);
+			lowest
+		},
 	}
-} */
+}
+
+/// Keeps the router's default port on a live listener, for apps that start
+/// after us or move to another port. The current port is kept for as long as
+/// it's listening, unless a listener the preferences favour turns up.
+pub async fn watch(router: Arc<Router>, preferences: Preferences) {
+	let mut interval = time::interval(WATCH_INTERVAL);
+	interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
-pub fn get_port_auto() -> Option<u16> {
+	let mut known: Option<BTreeMap<u16, (String, i32)>> = None;
+
+	loop {
+		interval.tick().await;
+
+		let listeners = match task::spawn_blocking(listeners).await {
+			Ok(listeners) => listeners,
+			Err(_) => continue,
+		};
+
+		let current: BTreeMap<u16, (String, i32)> = listeners.iter()
+			.map(|listener| (
+				listener.local_addr.port(),
+				(listener.process.name.clone(), listener.process.pid),
+			))
+			.collect();
+
+		// the first look only sets what's normal
+		if let Some(ref known) = known {
+			if current == *known {
+				continue
+			}
+
+			for (port, (name, pid)) in current.iter().filter(|(port, _)| !known.contains_key(port)) {
+				info!("{} ({}) started listening on port {}", name, pid, port);
+			}
+			for (port, (name, pid)) in known.iter().filter(|(port, _)| !current.contains_key(port)) {
+				info!("{} ({}) stopped listening on port {}", name, pid, port);
+			}
+		}
+
+		known = Some(current);
+
+		let candidates = preferred(listeners, &preferences);
+		let port = router.default_port();
+		let still_listening = |port| candidates.iter().any(|listener| listener.local_addr.port() == port);
+		let choice = match port {
+			Some(port) if still_listening(port) => Some(port),
+			_ => candidates.first().map(|listener| listener.local_addr.port()),
+		};
+
+		if choice != port {
+			router.set_default_port(choice);
+
+			match choice {
+				Some(choice) => info!("now intercepting port {}", choice),
+				None => info!("nothing is listening, not proxying"),
+			}
+		}
+	}
+}
+
+fn preferred(mut listeners: Vec<SockTabEntry>, preferences: &Preferences) -> Vec<SockTabEntry> {
+	if let Some(ref name) = preferences.process {
+		narrow(&mut listeners, |listener| listener.process.name == *name);
+	}
+	if let Some(ref ports) = preferences.ports {
+		narrow(&mut listeners, |listener| ports.contains(listener.local_addr.port()));
+	}
+
+	listeners
+}
+
+// listening sockets other processes could connect to, by port, except our own
+fn listeners() -> Vec<SockTabEntry> {
 	trace!("fetching addrs");
 
-	let mut _addrs: Vec<netstat::SockTabEntry> = vec![];
-	_addrs.append(&mut netstat::os_socks(netstat::SockType::TCP));
-	_addrs.append(&mut netstat::os_socks(netstat::SockType::TCP6));
+	let mut addrs = netstat::query(&Filter::listening(Some(Protocol::Tcp)));
+	addrs.retain(|addr| {
+		let ip = addr.local_addr.ip();
+		let port = addr.local_addr.port();
+
+		(ip.is_loopback() || ip.is_unspecified()) && port != PROXY_PORT && port != SERVER_PORT
+	});
 
-	trace!("filtering addrs");
+	// a server listening on both IPv4 and IPv6 shows up twice
+	addrs.sort_by_key(|addr| addr.local_addr.port());
+	addrs.dedup_by_key(|addr| addr.local_addr.port());
 
-	let mut addrs: Vec<netstat::SockTabEntry> = vec![];
-	for addr in _addrs.clone() {
-		let listening = addr.state == 10;
-		let unspecified = addr.local_addr.ip().is_unspecified();
-		let loopback = addr.local_addr.ip().is_loopback();
+	addrs
+}
+
+fn narrow(listeners: &mut Vec<SockTabEntry>, preferred: impl Fn(&SockTabEntry) -> bool) {
+	if listeners.iter().any(&preferred) {
+		listeners.retain(preferred);
+	}
+}
+
+fn ask(listeners: &[SockTabEntry]) -> Option<u16> {
+	println!("{} listeners detected:", listeners.len());
+
+	for (index, listener) in listeners.iter().enumerate() {
+		let process = &listener.process;
+		let command = if process.cmdline.is_empty() {
+			process.name.clone()
+		} else {
+			process.cmdline.join(" ")
+		};
+
+		println!(
+			"{}: {} ({}) - [{}]:{}",
+			index + 1,
+			command,
+			process.pid,
+			listener.local_addr.ip(),
+			listener.local_addr.port(),
+		);
+	}
+
+	loop {
+		print!("Which one should be used? [1-{}] ", listeners.len());
+		let _ = io::stdout().flush();
 
-		if listening && (loopback || unspecified) {
-			addrs.push(addr);
+		let mut input = String::new();
+		match io::stdin().read_line(&mut input) {
+			Ok(0) | Err(_) => {
+				warn!("no listener chosen, using the lowest port");
+				return None
+			},
+			Ok(_) => (),
+		}
+
+		match input.trim().parse::<usize>() {
+			Ok(choice) if (1..=listeners.len()).contains(&choice) =>
+				return Some(listeners[choice - 1].local_addr.port()),
+			_ => println!("Please enter a number from 1 to {}.", listeners.len()),
 		}
 	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::netstat::{ Process, SockType, TcpState };
 
-	if addrs.len() == 0 {
-		None
-	} else if addrs.len() == 1 {
-		Some(addrs[0].local_addr.port())
-	} else {
-		println!("{} listeners detected:", addrs.len());
-
-		let mut index = 1;
-		for addr in addrs.clone() {
-			println!(
-				"{}: {} ({}) - [{}]:{}",
-				index,
-				addr.process.name,
-				addr.process.pid,
-				addr.local_addr.ip(),
-				addr.local_addr.port()
-			);
-
-			index += 1;
+	fn listener(port: u16, name: &str) -> SockTabEntry {
+		SockTabEntry {
+			sock_type: SockType::Tcp,
+			local_addr: ([ 127, 0, 0, 1 ], port).into(),
+			remote_addr: ([ 0, 0, 0, 0 ], 0).into(),
+			state: TcpState::Listen,
+			uid: 1000,
+			process: Process { pid: port.into(), name: name.into(), cmdline: vec![] },
 		}
+	}
 
-		error!("TODO: this needs fixing!!!");
-		unimplemented!();
-
-		/* loop {
-			if let Some(choice) = input::prompt("Which one should be used?".to_string())
-				.and_then(
-					|input| usize::from_str(input.as_str())
-						.map(|ok| Some(ok - 1))
-						.unwrap_or(None)
-				)
-			{
-				return Some(addrs[choice].local_addr.port());
-			}
-		} */
+	fn ports(listeners: &[SockTabEntry]) -> Vec<u16> {
+		listeners.iter().map(|listener| listener.local_addr.port()).collect()
+	}
+
+	fn candidates() -> Vec<SockTabEntry> {
+		vec![ listener(3000, "node"), listener(5432, "postgres"), listener(8000, "python3"), listener(8080, "node") ]
+	}
+
+	#[test]
+	fn narrowing() {
+		let mut listeners = candidates();
+		narrow(&mut listeners, |listener| listener.process.name == "node");
+		assert_eq!(ports(&listeners), [ 3000, 8080 ]);
+
+		// a preference nothing matches is ignored rather than leaving nothing
+		narrow(&mut listeners, |listener| listener.process.name == "ruby");
+		assert_eq!(ports(&listeners), [ 3000, 8080 ]);
+
+		let mut listeners = vec![];
+		narrow(&mut listeners, |_| true);
+		assert!(listeners.is_empty());
+	}
+
+	#[test]
+	fn no_preferences() {
+		assert_eq!(ports(&preferred(candidates(), &Preferences::default())), [ 3000, 5432, 8000, 8080 ]);
+	}
+
+	#[test]
+	fn process_preference() {
+		let preferences = Preferences { process: Some("node".into()), ..Default::default() };
+		assert_eq!(ports(&preferred(candidates(), &preferences)), [ 3000, 8080 ]);
+
+		let preferences = Preferences { process: Some("ruby".into()), ..Default::default() };
+		assert_eq!(ports(&preferred(candidates(), &preferences)), [ 3000, 5432, 8000, 8080 ]);
+	}
+
+	#[test]
+	fn port_preference() {
+		let preferences = Preferences { ports: Some("8000-8999".parse().unwrap()), ..Default::default() };
+		assert_eq!(ports(&preferred(candidates(), &preferences)), [ 8000, 8080 ]);
+
+		let preferences = Preferences { ports: Some("9000".parse().unwrap()), ..Default::default() };
+		assert_eq!(ports(&preferred(candidates(), &preferences)), [ 3000, 5432, 8000, 8080 ]);
+	}
+
+	#[test]
+	fn combined_preferences() {
+		// the process narrows first, then the ports among what's left
+		let preferences = Preferences {
+			process: Some("node".into()),
+			ports: Some("8000-8999".parse().unwrap()),
+			ask: false,
+		};
+		assert_eq!(ports(&preferred(candidates(), &preferences)), [ 8080 ]);
+
+		// ports that only other processes listen on don't undo the process
+		let preferences = Preferences {
+			process: Some("node".into()),
+			ports: Some("5432".parse().unwrap()),
+			ask: false,
+		};
+		assert_eq!(ports(&preferred(candidates(), &preferences)), [ 3000, 8080 ]);
+
+		let preferences = Preferences {
+			process: Some("ruby".into()),
+			ports: Some("5432".parse().unwrap()),
+			ask: false,
+		};
+		assert_eq!(ports(&preferred(candidates(), &preferences)), [ 5432 ]);
 	}
 }
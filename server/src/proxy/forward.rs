@@ -0,0 +1,192 @@
+use super::http::{ self, Headers };
+use crate::config::ProxyProtocol;
+
+use std::net::{ IpAddr, Ipv6Addr, SocketAddr };
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+// version 2, PROXY command
+const V2_PROXY: u8 = 0x21;
+// address family and transport, both over TCP
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+/// Tells the backend which client a request came from, adding to what any
+/// proxy in front of us already said rather than replacing it.
+pub fn add_headers(headers: &mut Headers, client: SocketAddr) {
+	let ip = client.ip().to_canonical();
+
+	headers.append("X-Forwarded-For", &ip.to_string());
+	// whoever is in front of us knows how the client really connected, and
+	// both headers should say the same
+	let proto = match headers.get("X-Forwarded-Proto") {
+		Some(proto) => proto.split(',').next().unwrap_or_default().trim().to_string(),
+		None => {
+			headers.push("X-Forwarded-Proto", "http");
+			"http".into()
+		},
+	};
+
+	// RFC 7239 wants IPv6 addresses in brackets, which then need quoting
+	let mut element = match ip {
+		IpAddr::V4(ip) => format!("for={}", ip),
+		IpAddr::V6(ip) => format!("for=\"[{}]\"", ip),
+	};
+	if let Some(host) = headers.get("Host") {
+		element.push_str(";host=");
+		element.push_str(&quote(host));
+	}
+	element.push_str(";proto=");
+	element.push_str(&quote(&proto));
+
+	headers.append("Forwarded", &element);
+}
+
+/// The PROXY protocol header that starts a connection from `client` to `server`.
+pub fn proxy_header(version: ProxyProtocol, client: SocketAddr, server: SocketAddr) -> Vec<u8> {
+	let (client_ip, server_ip) = (client.ip().to_canonical(), server.ip().to_canonical());
+
+	match version {
+		ProxyProtocol::V1 => {
+			let (family, client_ip, server_ip) = match (client_ip, server_ip) {
+				(IpAddr::V4(client), IpAddr::V4(server)) =>
+					("TCP4", client.to_string(), server.to_string()),
+				_ => ("TCP6", to_v6(client_ip).to_string(), to_v6(server_ip).to_string()),
+			};
+
+			format!(
+				"PROXY {} {} {} {} {}\r\n",
+				family, client_ip, server_ip, client.port(), server.port(),
+			).into_bytes()
+		},
+		ProxyProtocol::V2 => {
+			let (family, mut addresses) = match (client_ip, server_ip) {
+				(IpAddr::V4(client), IpAddr::V4(server)) =>
+					(V2_TCP4, [ client.octets(), server.octets() ].concat()),
+				_ => (V2_TCP6, [ to_v6(client_ip).octets(), to_v6(server_ip).octets() ].concat()),
+			};
+			addresses.extend_from_slice(&client.port().to_be_bytes());
+			addresses.extend_from_slice(&server.port().to_be_bytes());
+
+			let mut header = V2_SIGNATURE.to_vec();
+			header.push(V2_PROXY);
+			header.push(family);
+			header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+			header.extend_from_slice(&addresses);
+
+			header
+		},
+	}
+}
+
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+	match ip {
+		IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+		IpAddr::V6(ip) => ip,
+	}
+}
+
+fn quote(value: &str) -> String {
+	if http::is_token(value) {
+		value.into()
+	} else {
+		format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn headers(fields: &[ (&str, &str) ]) -> Headers {
+		let mut headers = Headers::default();
+		for (name, value) in fields {
+			headers.push(name, value);
+		}
+
+		headers
+	}
+
+	#[test]
+	fn new_headers() {
+		let mut added = headers(&[ ("Host", "example.com") ]);
+		add_headers(&mut added, "203.0.113.7:51000".parse().unwrap());
+
+		assert_eq!(added, headers(&[
+			("Host", "example.com"),
+			("X-Forwarded-For", "203.0.113.7"),
+			("X-Forwarded-Proto", "http"),
+			("Forwarded", "for=203.0.113.7;host=example.com;proto=http"),
+		]));
+	}
+
+	#[test]
+	fn existing_headers() {
+		let mut added = headers(&[
+			("Host", "[::1]:8080"),
+			("X-Forwarded-For", "198.51.100.1"),
+			("X-Forwarded-Proto", "https"),
+			("Forwarded", "for=198.51.100.1;proto=https"),
+		]);
+		add_headers(&mut added, "[2001:db8::1]:51000".parse().unwrap());
+
+		assert_eq!(added, headers(&[
+			("Host", "[::1]:8080"),
+			("X-Forwarded-For", "198.51.100.1, 2001:db8::1"),
+			("X-Forwarded-Proto", "https"),
+			("Forwarded", "for=198.51.100.1;proto=https, for=\"[2001:db8::1]\";host=\"[::1]:8080\";proto=https"),
+		]));
+	}
+
+	#[test]
+	fn mapped_client() {
+		let mut added = Headers::default();
+		add_headers(&mut added, "[::ffff:192.0.2.1]:1".parse().unwrap());
+
+		assert_eq!(added.get("X-Forwarded-For"), Some("192.0.2.1"));
+		assert_eq!(added.get("Forwarded"), Some("for=192.0.2.1;proto=http"));
+	}
+
+	#[test]
+	fn v1_header() {
+		let client = "192.0.2.1:51000".parse().unwrap();
+		assert_eq!(
+			proxy_header(ProxyProtocol::V1, client, "10.0.0.2:3321".parse().unwrap()),
+			b"PROXY TCP4 192.0.2.1 10.0.0.2 51000 3321\r\n",
+		);
+		assert_eq!(
+			proxy_header(ProxyProtocol::V1, client, "[2001:db8::2]:3321".parse().unwrap()),
+			&b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 51000 3321\r\n"[..],
+		);
+	}
+
+	#[test]
+	fn v2_ipv4_header() {
+		let header = proxy_header(
+			ProxyProtocol::V2,
+			"[::ffff:192.0.2.1]:51000".parse().unwrap(),
+			"10.0.0.2:3321".parse().unwrap(),
+		);
+
+		let mut expected = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+		expected.extend_from_slice(&[ 0x21, 0x11, 0, 12 ]);
+		expected.extend_from_slice(&[ 192, 0, 2, 1, 10, 0, 0, 2 ]);
+		expected.extend_from_slice(&[ 0xc7, 0x38, 0x0c, 0xf9 ]);
+		assert_eq!(header, expected);
+	}
+
+	#[test]
+	fn v2_ipv6_header() {
+		let header = proxy_header(
+			ProxyProtocol::V2,
+			"[2001:db8::1]:51000".parse().unwrap(),
+			"10.0.0.2:3321".parse().unwrap(),
+		);
+
+		let mut expected = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+		expected.extend_from_slice(&[ 0x21, 0x21, 0, 36 ]);
+		expected.extend_from_slice(&[ 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1 ]);
+		expected.extend_from_slice(&[ 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 10, 0, 0, 2 ]);
+		expected.extend_from_slice(&[ 0xc7, 0x38, 0x0c, 0xf9 ]);
+		assert_eq!(header, expected);
+	}
+}
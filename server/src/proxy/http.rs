@@ -0,0 +1,496 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::str;
+
+/// The most we buffer while waiting for the end of a request or response head.
+pub const MAX_HEAD_SIZE: usize = 65536;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParseError {
+	Malformed,
+	TooLarge,
+	BadLength,
+	/// A request's `Transfer-Encoding` doesn't end in `chunked`.
+	BadEncoding,
+	/// A request has both `Transfer-Encoding` and `Content-Length`.
+	AmbiguousLength,
+}
+
+impl fmt::Display for ParseError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Malformed => write!(f, "malformed message head"),
+			Self::TooLarge => write!(f, "message head is larger than {} bytes", MAX_HEAD_SIZE),
+			Self::BadLength => write!(f, "invalid Content-Length"),
+			Self::BadEncoding => write!(f, "Transfer-Encoding does not end in chunked"),
+			Self::AmbiguousLength => write!(f, "both Transfer-Encoding and Content-Length are set"),
+		}
+	}
+}
+
+impl error::Error for ParseError {}
+
+/// Parses a head from the start of some data, returning it and its length, or
+/// `None` if more data is needed.
+pub type Parser<T> = fn(&[u8]) -> Result<Option<(T, usize)>, ParseError>;
+
+/// How the body following a head is delimited.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Body {
+	None,
+	Length(u64),
+	Chunked,
+	UntilClose,
+}
+
+/// Header fields in the order they arrived, with their original case.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+	pub fn get(&self, name: &str) -> Option<&str> {
+		self.0.iter()
+			.find(|(key, _)| key.eq_ignore_ascii_case(name))
+			.map(|(_, value)| value.as_str())
+	}
+
+	/// Whether a comma-separated header like `Connection` lists `token`.
+	pub fn has_token(&self, name: &str, token: &str) -> bool {
+		self.0.iter()
+			.filter(|(key, _)| key.eq_ignore_ascii_case(name))
+			.flat_map(|(_, value)| value.split(','))
+			.any(|item| item.trim().eq_ignore_ascii_case(token))
+	}
+
+	pub fn push(&mut self, name: &str, value: &str) {
+		self.0.push((name.into(), value.into()));
+	}
+
+	/// Adds `value` to the end of a list header, extending the last field with
+	/// that name rather than adding another.
+	pub fn append(&mut self, name: &str, value: &str) {
+		match self.0.iter_mut().rev().find(|(key, _)| key.eq_ignore_ascii_case(name)) {
+			Some((_, list)) => {
+				list.push_str(", ");
+				list.push_str(value);
+			},
+			None => self.push(name, value),
+		}
+	}
+
+	fn body(&self) -> Result<Option<Body>, ParseError> {
+		if let Some(encoding) = self.get("Transfer-Encoding") {
+			let last = encoding.rsplit(',').next().unwrap_or_default();
+			return Ok(Some(if last.trim().eq_ignore_ascii_case("chunked") {
+				Body::Chunked
+			} else {
+				Body::UntilClose
+			}))
+		}
+
+		let mut lengths = self.0.iter()
+			.filter(|(key, _)| key.eq_ignore_ascii_case("Content-Length"))
+			.flat_map(|(_, value)| value.split(','))
+			.map(|value| value.trim().parse::<u64>().map_err(|_| ParseError::BadLength));
+
+		match lengths.next() {
+			Some(length) => {
+				let length = length?;
+				// repeats are only allowed when they all agree
+				for other in lengths {
+					if other? != length {
+						return Err(ParseError::BadLength)
+					}
+				}
+
+				Ok(Some(Body::Length(length)))
+			},
+			None => Ok(None),
+		}
+	}
+
+	fn write(&self, out: &mut Vec<u8>) {
+		for (name, value) in &self.0 {
+			out.extend_from_slice(name.as_bytes());
+			out.extend_from_slice(b": ");
+			out.extend_from_slice(value.as_bytes());
+			out.extend_from_slice(b"\r\n");
+		}
+
+		out.extend_from_slice(b"\r\n");
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+	pub method: String,
+	pub target: String,
+	pub version: String,
+	pub headers: Headers,
+}
+
+impl Request {
+	/// Parses a request head from the start of `data`, returning it and its
+	/// length, or `None` if more data is needed.
+	pub fn parse(data: &[u8]) -> Result<Option<(Self, usize)>, ParseError> {
+		let (start, headers, length) = match parse_head(data)? {
+			Some(head) => head,
+			None => return Ok(None),
+		};
+
+		let mut parts = start.split(' ');
+		let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+			(Some(method), Some(target), Some(version), None)
+				if is_token(method) && !target.is_empty() && version.starts_with("HTTP/1.") =>
+				(method, target, version),
+			_ => return Err(ParseError::Malformed),
+		};
+
+		Ok(Some((
+			Self {
+				method: method.into(),
+				target: target.into(),
+				version: version.into(),
+				headers,
+			},
+			length,
+		)))
+	}
+
+	pub fn body(&self) -> Result<Body, ParseError> {
+		match self.headers.body()? {
+			// the backend could frame the body by either, so the request could
+			// smuggle another one past us
+			Some(Body::Chunked | Body::UntilClose) if self.headers.get("Content-Length").is_some() =>
+				Err(ParseError::AmbiguousLength),
+			// only a response can run until the connection closes
+			Some(Body::UntilClose) => Err(ParseError::BadEncoding),
+			// requests without a length have no body
+			body => Ok(body.unwrap_or(Body::None)),
+		}
+	}
+
+	pub fn keep_alive(&self) -> bool {
+		keep_alive(&self.version, &self.headers)
+	}
+
+	pub fn expects_continue(&self) -> bool {
+		self.headers.get("Expect")
+			.is_some_and(|expect| expect.trim().eq_ignore_ascii_case("100-continue"))
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut out = format!("{} {} {}\r\n", self.method, self.target, self.version).into_bytes();
+		self.headers.write(&mut out);
+
+		out
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+	pub version: String,
+	pub status: u16,
+	pub headers: Headers,
+}
+
+impl Response {
+	/// Parses a response head from the start of `data`, returning it and its
+	/// length, or `None` if more data is needed.
+	pub fn parse(data: &[u8]) -> Result<Option<(Self, usize)>, ParseError> {
+		let (start, headers, length) = match parse_head(data)? {
+			Some(head) => head,
+			None => return Ok(None),
+		};
+
+		let mut parts = start.splitn(3, ' ');
+		let (version, status) = match (parts.next(), parts.next()) {
+			(Some(version), Some(status)) if version.starts_with("HTTP/1.") && status.len() == 3 =>
+				(version, status.parse().map_err(|_| ParseError::Malformed)?),
+			_ => return Err(ParseError::Malformed),
+		};
+
+		Ok(Some((Self { version: version.into(), status, headers }, length)))
+	}
+
+	/// How the body is delimited, given the method of the request it answers.
+	pub fn body(&self, method: &str) -> Result<Body, ParseError> {
+		if method == "HEAD" || self.is_informational() || self.status == 204 || self.status == 304 {
+			return Ok(Body::None)
+		}
+
+		Ok(self.headers.body()?.unwrap_or(Body::UntilClose))
+	}
+
+	/// Whether this is a 1xx response, which is followed by another response
+	/// to the same request. 101 is not, since the protocol changes after it.
+	pub fn is_informational(&self) -> bool {
+		(100..200).contains(&self.status) && self.status != 101
+	}
+
+	pub fn keep_alive(&self) -> bool {
+		keep_alive(&self.version, &self.headers)
+	}
+}
+
+/// Finds the end of a chunk-size line at the start of `data`, returning the
+/// chunk size and the length of the line, or `None` if more data is needed.
+pub fn parse_chunk_size(data: &[u8]) -> Result<Option<(u64, usize)>, ParseError> {
+	let end = match data.iter().position(|byte| *byte == b'\n') {
+		Some(end) => end,
+		None if data.len() > MAX_HEAD_SIZE => return Err(ParseError::TooLarge),
+		None => return Ok(None),
+	};
+
+	let line = str::from_utf8(&data[..end]).map_err(|_| ParseError::Malformed)?;
+	// chunk extensions follow a semicolon, and are ignored
+	let size = line.split(';').next().unwrap_or_default().trim();
+
+	u64::from_str_radix(size, 16)
+		.map(|size| Some((size, end + 1)))
+		.map_err(|_| ParseError::Malformed)
+}
+
+fn keep_alive(version: &str, headers: &Headers) -> bool {
+	if headers.has_token("Connection", "close") {
+		false
+	} else {
+		version != "HTTP/1.0" || headers.has_token("Connection", "keep-alive")
+	}
+}
+
+fn parse_head(data: &[u8]) -> Result<Option<(&str, Headers, usize)>, ParseError> {
+	let length = match find_head_end(data) {
+		Some(length) => length,
+		None if data.len() > MAX_HEAD_SIZE => return Err(ParseError::TooLarge),
+		None => return Ok(None),
+	};
+
+	let head = str::from_utf8(&data[..length]).map_err(|_| ParseError::Malformed)?;
+	// a few empty lines before a request are allowed, for sloppy clients
+	let mut lines = head.lines().skip_while(|line| line.is_empty());
+	let start = lines.next().ok_or(ParseError::Malformed)?;
+
+	let mut headers = Headers::default();
+	for line in lines.filter(|line| !line.is_empty()) {
+		let (name, value) = line.split_once(':').ok_or(ParseError::Malformed)?;
+		if !is_token(name) {
+			return Err(ParseError::Malformed)
+		}
+
+		headers.push(name, value.trim());
+	}
+
+	Ok(Some((start, headers, length)))
+}
+
+// the head ends at the first empty line, which may or may not use CRLF
+fn find_head_end(data: &[u8]) -> Option<usize> {
+	let start = data.iter().position(|byte| !matches!(byte, b'\r' | b'\n'))?;
+
+	data[start..].windows(2)
+		.enumerate()
+		.find_map(|(index, window)| match window {
+			[ b'\n', b'\n' ] => Some(start + index + 2),
+			[ b'\n', b'\r' ] if data.get(start + index + 2) == Some(&b'\n') => Some(start + index + 3),
+			_ => None,
+		})
+}
+
+pub fn is_token(s: &str) -> bool {
+	!s.is_empty() && s.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+impl From<ParseError> for io::Error {
+	fn from(error: ParseError) -> Self {
+		io::Error::new(io::ErrorKind::InvalidData, error)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::proxy::Conn;
+
+	fn request(head: &str) -> Request {
+		Request::parse(head.as_bytes()).unwrap().unwrap().0
+	}
+
+	fn response(head: &str) -> Response {
+		Response::parse(head.as_bytes()).unwrap().unwrap().0
+	}
+
+	async fn copy(body: Body, data: &[u8]) -> (io::Result<()>, Vec<u8>, Vec<u8>) {
+		let mut conn = Conn::new(data);
+		let mut out = Vec::new();
+		let result = conn.copy_body(body, &mut out).await;
+
+		(result, out, conn.buffer.iter().copied().chain(conn.stream.iter().copied()).collect())
+	}
+
+	#[test]
+	fn head_end() {
+		assert_eq!(find_head_end(b"GET / HTTP/1.1\r\nHost: a\r\n\r\nbody"), Some(27));
+		assert_eq!(find_head_end(b"GET / HTTP/1.1\nHost: a\n\nbody"), Some(24));
+		assert_eq!(find_head_end(b"\r\n\r\nGET / HTTP/1.1\r\n\r\n"), Some(22));
+		assert_eq!(find_head_end(b"GET / HTTP/1.1\r\nHost: a\r\n"), None);
+		assert_eq!(find_head_end(b"\r\n\r\n"), None);
+	}
+
+	#[test]
+	fn parse_request() {
+		let data = b"\r\nPOST /a?b HTTP/1.1\r\nHost: example.com\r\nX-Thing:  spaced \r\n\r\nbody";
+		let (request, length) = Request::parse(data).unwrap().unwrap();
+
+		assert_eq!(length, data.len() - 4);
+		assert_eq!((request.method.as_str(), request.target.as_str()), ("POST", "/a?b"));
+		assert_eq!(request.headers.get("host"), Some("example.com"));
+		assert_eq!(request.headers.get("X-Thing"), Some("spaced"));
+		assert_eq!(request.to_bytes(), &b"POST /a?b HTTP/1.1\r\nHost: example.com\r\nX-Thing: spaced\r\n\r\n"[..]);
+
+		assert_eq!(Request::parse(b"GET / HTTP/1.1\r\nHost: a\r\n"), Ok(None));
+		assert_eq!(Request::parse(b"GET /  HTTP/1.1\r\n\r\n"), Err(ParseError::Malformed));
+		assert_eq!(Request::parse(b"GET / SPDY/3\r\n\r\n"), Err(ParseError::Malformed));
+		assert_eq!(Request::parse(b"GET / HTTP/1.1\r\nBad Name: x\r\n\r\n"), Err(ParseError::Malformed));
+		assert_eq!(Request::parse(b"GET / HTTP/1.1\r\nNo colon\r\n\r\n"), Err(ParseError::Malformed));
+	}
+
+	#[test]
+	fn head_too_large() {
+		let mut data = b"GET / HTTP/1.1\r\n".to_vec();
+		data.extend_from_slice(&[ b'a'; MAX_HEAD_SIZE ]);
+		assert_eq!(Request::parse(&data), Err(ParseError::TooLarge));
+		assert_eq!(Response::parse(&data), Err(ParseError::TooLarge));
+
+		// only once there's more than the limit without an end
+		assert_eq!(Request::parse(&data[..MAX_HEAD_SIZE]), Ok(None));
+	}
+
+	#[test]
+	fn request_body() {
+		assert_eq!(request("GET / HTTP/1.1\r\n\r\n").body(), Ok(Body::None));
+		assert_eq!(request("POST / HTTP/1.1\r\nContent-Length: 12\r\n\r\n").body(), Ok(Body::Length(12)));
+		assert_eq!(
+			request("POST / HTTP/1.1\r\nTransfer-Encoding: gzip, Chunked\r\n\r\n").body(),
+			Ok(Body::Chunked),
+		);
+
+		// repeats that agree are fine
+		assert_eq!(
+			request("POST / HTTP/1.1\r\nContent-Length: 5, 5\r\nContent-Length: 5\r\n\r\n").body(),
+			Ok(Body::Length(5)),
+		);
+		assert_eq!(
+			request("POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n").body(),
+			Err(ParseError::BadLength),
+		);
+		assert_eq!(request("POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n").body(), Err(ParseError::BadLength));
+		assert_eq!(request("POST / HTTP/1.1\r\nContent-Length: 0x10\r\n\r\n").body(), Err(ParseError::BadLength));
+	}
+
+	#[test]
+	fn request_smuggling() {
+		assert_eq!(
+			request("POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n").body(),
+			Err(ParseError::AmbiguousLength),
+		);
+		assert_eq!(
+			request("POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\nContent-Length: 5\r\n\r\n").body(),
+			Err(ParseError::AmbiguousLength),
+		);
+		assert_eq!(
+			request("POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n").body(),
+			Err(ParseError::BadEncoding),
+		);
+	}
+
+	#[test]
+	fn response_body() {
+		let ok = response("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n");
+		assert_eq!(ok.body("GET"), Ok(Body::Length(3)));
+		assert_eq!(ok.body("HEAD"), Ok(Body::None));
+
+		for status in &[ 100, 103, 204, 304 ] {
+			let head = format!("HTTP/1.1 {} Whatever\r\nContent-Length: 3\r\n\r\n", status);
+			assert_eq!(response(&head).body("GET"), Ok(Body::None), "status {}", status);
+		}
+
+		assert!(response("HTTP/1.1 100 Continue\r\n\r\n").is_informational());
+		assert!(!response("HTTP/1.1 101 Switching Protocols\r\n\r\n").is_informational());
+
+		assert_eq!(response("HTTP/1.1 200 OK\r\n\r\n").body("GET"), Ok(Body::UntilClose));
+		assert_eq!(
+			response("HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\n").body("GET"),
+			Ok(Body::UntilClose),
+		);
+		assert_eq!(
+			response("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n").body("GET"),
+			Ok(Body::Chunked),
+		);
+
+		assert_eq!(Response::parse(b"HTTP/1.1 20 OK\r\n\r\n"), Err(ParseError::Malformed));
+		assert_eq!(Response::parse(b"HTTP/1.1 abc OK\r\n\r\n"), Err(ParseError::Malformed));
+	}
+
+	#[test]
+	fn keep_alive() {
+		assert!(request("GET / HTTP/1.1\r\n\r\n").keep_alive());
+		assert!(!request("GET / HTTP/1.1\r\nConnection: Upgrade, close\r\n\r\n").keep_alive());
+		assert!(!request("GET / HTTP/1.0\r\n\r\n").keep_alive());
+		assert!(request("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").keep_alive());
+
+		assert!(response("HTTP/1.1 200 OK\r\n\r\n").keep_alive());
+		assert!(!response("HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n").keep_alive());
+		assert!(!response("HTTP/1.0 200 OK\r\n\r\n").keep_alive());
+	}
+
+	#[test]
+	fn chunk_size() {
+		assert_eq!(parse_chunk_size(b"1a\r\ndata"), Ok(Some((26, 4))));
+		assert_eq!(parse_chunk_size(b"A;name=value\r\n"), Ok(Some((10, 14))));
+		assert_eq!(parse_chunk_size(b"0\n"), Ok(Some((0, 2))));
+		assert_eq!(parse_chunk_size(b"1a"), Ok(None));
+		assert_eq!(parse_chunk_size(b"zz\r\n"), Err(ParseError::Malformed));
+		assert_eq!(parse_chunk_size(b"\r\n"), Err(ParseError::Malformed));
+		assert_eq!(parse_chunk_size(&[ b'0'; MAX_HEAD_SIZE + 1 ]), Err(ParseError::TooLarge));
+	}
+
+	#[tokio::test]
+	async fn copy_chunked() {
+		let body = b"4\r\nWiki\r\n5;ext\r\npedia\r\n0\r\nExpires: never\r\n\r\n";
+		let mut data = body.to_vec();
+		data.extend_from_slice(b"GET /next HTTP/1.1\r\n\r\n");
+
+		let (result, out, rest) = copy(Body::Chunked, &data).await;
+		result.unwrap();
+		assert_eq!(out, &body[..]);
+		assert_eq!(rest, b"GET /next HTTP/1.1\r\n\r\n");
+
+		let (result, out, _) = copy(Body::Chunked, b"3\r\nabc\r\n0\r\n\r\n").await;
+		result.unwrap();
+		assert_eq!(out, b"3\r\nabc\r\n0\r\n\r\n");
+
+		let (result, _, _) = copy(Body::Chunked, b"3\r\nabc\r\n0\r\nExpires: never\r\n").await;
+		assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+		let (result, _, _) = copy(Body::Chunked, b"nope\r\n").await;
+		assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+	}
+
+	#[tokio::test]
+	async fn copy_length() {
+		let (result, out, rest) = copy(Body::Length(5), b"helloGET").await;
+		result.unwrap();
+		assert_eq!((&out[..], &rest[..]), (&b"hello"[..], &b"GET"[..]));
+
+		let (result, out, _) = copy(Body::Length(10), b"short").await;
+		assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+		assert_eq!(out, b"short");
+
+		let (result, out, rest) = copy(Body::UntilClose, b"all of it").await;
+		result.unwrap();
+		assert_eq!((&out[..], &rest[..]), (&b"all of it"[..], &b""[..]));
+	}
+}
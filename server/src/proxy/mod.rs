@@ -0,0 +1,339 @@
+use crate::PROXY_PORT;
+use crate::config::ProxyProtocol;
+
+mod forward;
+mod http;
+mod route;
+
+pub use route::Router;
+
+use http::{ Body, Request, Response };
+use route::Destination;
+
+use std::io::{ self, ErrorKind };
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{ self as aio, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt };
+use tokio::net::{ TcpListener, TcpStream };
+use tokio::sync::oneshot;
+use tokio::time::{ self, Instant };
+
+const BUFFER_SIZE: usize = 8192;
+/// How long a new connection can stay quiet before it's taken not to be HTTP.
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(1);
+const BAD_REQUEST: &[u8] = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+const BAD_GATEWAY: &[u8] = b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+pub async fn start(router: Arc<Router>, mut signaler: oneshot::Receiver<()>) -> io::Result<()> {
+	info!("proxy running");
+
+	let address = format!("0.0.0.0:{}", PROXY_PORT);
+	let listener = TcpListener::bind(address.as_str()).await?;
+
+	loop {
+		tokio::select! {
+			accepted = listener.accept() => if let Ok((stream, _)) = accepted {
+				trace!("received stream");
+
+				let router = router.clone();
+				tokio::spawn(async move {
+					if let Err(error) = handle_stream(stream, &router).await {
+						warn!("stream handler failed: {}", error);
+					}
+				});
+			},
+			_ = &mut signaler => break,
+		}
+	}
+
+	Ok(())
+}
+
+/// Relays HTTP requests one at a time, routing each of them separately so a
+/// keep-alive connection can reach different backends. Anything that isn't
+/// HTTP goes straight to the fallback port, and upgraded connections are
+/// passed through untouched once the backend accepts the upgrade.
+async fn handle_stream(stream: TcpStream, router: &Router) -> io::Result<()> {
+	let addresses = (stream.peer_addr()?, stream.local_addr()?);
+	let mut client = Conn::new(stream);
+
+	// read until it's clear whether this is HTTP. the server speaks first in
+	// some protocols, so a client that stays quiet isn't waited on for long
+	let deadline = Instant::now() + SNIFF_TIMEOUT;
+	let is_http = loop {
+		match maybe_http(&client.buffer) {
+			Some(is_http) => break is_http,
+			None => match time::timeout_at(deadline, client.fill()).await {
+				Ok(filled) => if !filled? {
+					return Ok(())
+				},
+				Err(_) => break false,
+			},
+		}
+	};
+
+	if !is_http {
+		let destination = router.fallback().ok_or(ErrorKind::AddrNotAvailable)?;
+		let server = connect(&destination, addresses).await?;
+
+		return client.tunnel(server).await
+	}
+
+	let mut backend: Option<(Backend, Conn)> = None;
+
+	loop {
+		let mut request = match client.read_head(Request::parse).await {
+			Ok(Some((request, _))) => request,
+			Ok(None) => return Ok(()),
+			Err(error) => {
+				let _ = client.stream.write_all(BAD_REQUEST).await;
+				return Err(error)
+			},
+		};
+
+		let body = match request.body() {
+			Ok(body) => body,
+			Err(error) => {
+				client.stream.write_all(BAD_REQUEST).await?;
+				return Err(error.into())
+			},
+		};
+
+		let destination = match router.route(request.headers.get("Host"), &request.target) {
+			Some(destination) => destination,
+			None => {
+				client.stream.write_all(BAD_GATEWAY).await?;
+				return Err(ErrorKind::AddrNotAvailable.into())
+			},
+		};
+
+		if let Some(ref target) = destination.target {
+			trace!("rewriting {} to {}", request.target, target);
+			request.target = target.clone();
+		}
+
+		if destination.forwarding.forwarded_headers {
+			forward::add_headers(&mut request.headers, addresses.0);
+		}
+
+		// reuse the last backend connection if it was opened the same way
+		let key = (destination.port, destination.forwarding.proxy_protocol);
+		let mut server = match backend.take() {
+			Some((last, server)) if last == key && server.is_open() => server,
+			_ => match connect(&destination, addresses).await {
+				Ok(server) => server,
+				Err(error) => {
+					client.stream.write_all(BAD_GATEWAY).await?;
+					return Err(error)
+				},
+			},
+		};
+
+		server.stream.write_all(&request.to_bytes()).await?;
+
+		// with `Expect: 100-continue` the body waits for the backend to ask for it
+		let mut body_sent = !request.expects_continue();
+		if body_sent {
+			client.copy_body(body, &mut server.stream).await?;
+		}
+
+		let response = loop {
+			let (response, head) = match server.read_head(Response::parse).await? {
+				Some(response) => response,
+				None => {
+					client.stream.write_all(BAD_GATEWAY).await?;
+					return Err(ErrorKind::UnexpectedEof.into())
+				},
+			};
+
+			client.stream.write_all(&head).await?;
+
+			if response.status == 100 && !body_sent {
+				client.copy_body(body, &mut server.stream).await?;
+				body_sent = true;
+			}
+
+			if !response.is_informational() {
+				break response
+			}
+		};
+
+		if response.status == 101 {
+			trace!("passing through upgraded connection to port {}", destination.port);
+			return client.tunnel(server).await
+		}
+
+		let response_body = response.body(&request.method)?;
+		server.copy_body(response_body, &mut client.stream).await?;
+
+		// a body the client never sent would be taken for the next request
+		if !body_sent || !request.keep_alive() || !response.keep_alive() || response_body == Body::UntilClose {
+			client.stream.shutdown().await?;
+			return Ok(())
+		}
+
+		backend = Some((key, server));
+	}
+}
+
+// a backend port, and the PROXY protocol its connection was opened with
+type Backend = (u16, Option<ProxyProtocol>);
+
+async fn connect(
+	destination: &Destination,
+	(client, local): (SocketAddr, SocketAddr),
+) -> io::Result<Conn> {
+	let mut stream = TcpStream::connect(("127.0.0.1", destination.port)).await?;
+
+	if let Some(version) = destination.forwarding.proxy_protocol {
+		stream.write_all(&forward::proxy_header(version, client, local)).await?;
+	}
+
+	Ok(Conn::new(stream))
+}
+
+/// A stream along with whatever has been read from it but not yet relayed.
+struct Conn<S = TcpStream> {
+	stream: S,
+	buffer: Vec<u8>,
+}
+
+impl<S: AsyncRead + Unpin> Conn<S> {
+	fn new(stream: S) -> Self {
+		Self { stream, buffer: Vec::new() }
+	}
+
+	/// Reads more into the buffer, returning false at the end of the stream.
+	async fn fill(&mut self) -> io::Result<bool> {
+		let mut data = [ 0; BUFFER_SIZE ];
+		let read = self.stream.read(&mut data).await?;
+		self.buffer.extend_from_slice(&data[..read]);
+
+		Ok(read > 0)
+	}
+
+	/// Reads a head, returning it along with its raw bytes, or `None` if the
+	/// stream ended cleanly before it started.
+	async fn read_head<T>(&mut self, parse: http::Parser<T>) -> io::Result<Option<(T, Vec<u8>)>> {
+		loop {
+			if let Some((head, length)) = parse(&self.buffer)? {
+				return Ok(Some((head, self.buffer.drain(..length).collect())))
+			}
+
+			if !self.fill().await? {
+				return if self.buffer.iter().all(u8::is_ascii_whitespace) {
+					Ok(None)
+				} else {
+					Err(ErrorKind::UnexpectedEof.into())
+				}
+			}
+		}
+	}
+
+	async fn copy_body<W: AsyncWrite + Unpin>(&mut self, body: Body, to: &mut W) -> io::Result<()> {
+		match body {
+			Body::None => Ok(()),
+			Body::Length(length) => self.copy_exact(length, to).await,
+			Body::Chunked => self.copy_chunked(to).await,
+			Body::UntilClose => {
+				to.write_all(&self.buffer).await?;
+				self.buffer.clear();
+				aio::copy(&mut self.stream, to).await?;
+
+				Ok(())
+			},
+		}
+	}
+
+	async fn copy_exact<W: AsyncWrite + Unpin>(&mut self, mut length: u64, to: &mut W) -> io::Result<()> {
+		while length > 0 {
+			if self.buffer.is_empty() && !self.fill().await? {
+				return Err(ErrorKind::UnexpectedEof.into())
+			}
+
+			let available = (self.buffer.len() as u64).min(length) as usize;
+			to.write_all(&self.buffer[..available]).await?;
+			self.buffer.drain(..available);
+			length -= available as u64;
+		}
+
+		Ok(())
+	}
+
+	async fn copy_chunked<W: AsyncWrite + Unpin>(&mut self, to: &mut W) -> io::Result<()> {
+		loop {
+			let (size, line) = match http::parse_chunk_size(&self.buffer)? {
+				Some(chunk) => chunk,
+				None => {
+					if !self.fill().await? {
+						return Err(ErrorKind::UnexpectedEof.into())
+					}
+
+					continue
+				},
+			};
+
+			self.copy_exact(line as u64, to).await?;
+
+			if size == 0 {
+				return self.copy_trailers(to).await
+			}
+
+			// the chunk and the CRLF after it
+			self.copy_exact(size + 2, to).await?;
+		}
+	}
+
+	async fn copy_trailers<W: AsyncWrite + Unpin>(&mut self, to: &mut W) -> io::Result<()> {
+		loop {
+			let end = match self.buffer.iter().position(|byte| *byte == b'\n') {
+				Some(end) => end,
+				None if self.buffer.len() > http::MAX_HEAD_SIZE => return Err(ErrorKind::InvalidData.into()),
+				None => {
+					if !self.fill().await? {
+						return Err(ErrorKind::UnexpectedEof.into())
+					}
+
+					continue
+				},
+			};
+
+			let done = self.buffer[..end].iter().all(|byte| *byte == b'\r');
+			self.copy_exact(end as u64 + 1, to).await?;
+
+			if done {
+				return Ok(())
+			}
+		}
+	}
+}
+
+impl Conn {
+	// whether the other end is still there and hasn't sent anything unasked
+	fn is_open(&self) -> bool {
+		self.buffer.is_empty() && matches!(
+			self.stream.try_read(&mut [ 0; 1 ]),
+			Err(ref error) if error.kind() == ErrorKind::WouldBlock
+		)
+	}
+
+	/// Relays everything in both directions until either side closes.
+	async fn tunnel(mut self, mut other: Self) -> io::Result<()> {
+		other.stream.write_all(&self.buffer).await?;
+		self.stream.write_all(&other.buffer).await?;
+
+		aio::copy_bidirectional(&mut self.stream, &mut other.stream).await?;
+
+		Ok(())
+	}
+}
+
+// whether this could be the start of a request line, or `None` if it's too
+// early to tell
+fn maybe_http(head: &[u8]) -> Option<bool> {
+	head.iter()
+		.position(|byte| !byte.is_ascii_uppercase())
+		.map(|method_end| method_end > 0 && head[method_end] == b' ')
+}
@@ -0,0 +1,248 @@
+use crate::SERVER_PORT;
+use crate::config::{ Forwarding, Route };
+
+use std::sync::atomic::{ AtomicU16, Ordering };
+
+const SERVER_PATH: &str = "/__atbws";
+
+/// Decides which port each request is forwarded to.
+///
+/// Requests for the tunnel's own endpoint always go to the websocket server.
+/// Otherwise the most specific matching route wins, preferring routes with a
+/// `host` and then longer paths, with ties going to the route listed first.
+/// Anything left over goes to the default port, if there is one, which can
+/// change while requests are being routed.
+#[derive(Debug)]
+pub struct Router {
+	routes: Vec<Route>,
+	// zero when there isn't one
+	default_port: AtomicU16,
+	default_forwarding: Forwarding,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Destination {
+	pub port: u16,
+	/// The request target to send instead, if the route rewrote it.
+	pub target: Option<String>,
+	pub forwarding: Forwarding,
+}
+
+impl Router {
+	pub fn new(
+		routes: Vec<Route>,
+		default_port: Option<u16>,
+		default_forwarding: Forwarding,
+	) -> Result<Self, String> {
+		if let Some(route) = routes.iter().find(|route| !route.path.starts_with('/')) {
+			return Err(format!("route path {} does not start with /", route.path))
+		}
+
+		Ok(Self {
+			routes,
+			default_port: AtomicU16::new(default_port.unwrap_or(0)),
+			default_forwarding,
+		})
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.routes.is_empty() && self.default_port().is_none()
+	}
+
+	pub fn default_port(&self) -> Option<u16> {
+		Some(self.default_port.load(Ordering::Relaxed)).filter(|port| *port != 0)
+	}
+
+	/// Changes where requests no route matches go, returning the old port.
+	pub fn set_default_port(&self, port: Option<u16>) -> Option<u16> {
+		Some(self.default_port.swap(port.unwrap_or(0), Ordering::Relaxed)).filter(|port| *port != 0)
+	}
+
+	/// Routes a request by its `Host` header and request target.
+	pub fn route(&self, host: Option<&str>, target: &str) -> Option<Destination> {
+		if target.starts_with(SERVER_PATH) {
+			// the websocket server knows its clients only by their keys
+			return Some(Destination {
+				port: SERVER_PORT,
+				target: None,
+				forwarding: Forwarding { forwarded_headers: false, proxy_protocol: None },
+			})
+		}
+
+		let host = host.map(without_port);
+		let route = self.routes.iter()
+			.rev()
+			.filter(|route| match (route.host.as_deref(), host) {
+				(Some(expected), Some(host)) => expected.eq_ignore_ascii_case(host),
+				(Some(_), None) => false,
+				(None, _) => true,
+			})
+			.filter(|route| has_prefix(target, &route.path))
+			.max_by_key(|route| (route.host.is_some(), route.path.len()));
+
+		match route {
+			Some(route) => Some(Destination {
+				port: route.port,
+				target: if route.strip_prefix {
+					Some(strip_prefix(target, &route.path))
+				} else {
+					None
+				},
+				forwarding: route.forwarding,
+			}),
+			None => self.fallback(),
+		}
+	}
+
+	/// Where to send traffic that isn't HTTP, or has no usable request line.
+	pub fn fallback(&self) -> Option<Destination> {
+		self.default_port().map(|port| Destination {
+			port,
+			target: None,
+			forwarding: self.default_forwarding,
+		})
+	}
+}
+
+// `/api` matches `/api`, `/api/users` and `/api?q`, but not `/apiary`
+fn has_prefix(target: &str, prefix: &str) -> bool {
+	let prefix = prefix.trim_end_matches('/');
+
+	match target.strip_prefix(prefix) {
+		Some(rest) => prefix.is_empty() || rest.is_empty() || rest.starts_with(&[ '/', '?', '#' ][..]),
+		None => false,
+	}
+}
+
+fn strip_prefix(target: &str, prefix: &str) -> String {
+	let rest = &target[prefix.trim_end_matches('/').len()..];
+
+	if rest.starts_with('/') {
+		rest.into()
+	} else {
+		format!("/{}", rest)
+	}
+}
+
+fn without_port(host: &str) -> &str {
+	if host.starts_with('[') {
+		// an IPv6 literal, like [::1]:8080
+		match host.find(']') {
+			Some(end) => &host[..=end],
+			None => host,
+		}
+	} else {
+		host.split(':').next().unwrap_or(host)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn route(host: Option<&str>, path: &str, port: u16) -> Route {
+		Route {
+			path: path.into(),
+			host: host.map(Into::into),
+			port,
+			strip_prefix: false,
+			forwarding: Forwarding::default(),
+		}
+	}
+
+	fn port(router: &Router, host: Option<&str>, target: &str) -> Option<u16> {
+		router.route(host, target).map(|destination| destination.port)
+	}
+
+	#[test]
+	fn host_before_path() {
+		let router = Router::new(vec![
+			route(None, "/api/v1", 1),
+			route(Some("app.example.com"), "/", 2),
+			route(Some("app.example.com"), "/api", 3),
+		], None, Forwarding::default()).unwrap();
+
+		assert_eq!(port(&router, Some("app.example.com"), "/api/v1/users"), Some(3));
+		assert_eq!(port(&router, Some("APP.example.com:8080"), "/other"), Some(2));
+		assert_eq!(port(&router, Some("other.example.com"), "/api/v1/users"), Some(1));
+		assert_eq!(port(&router, None, "/api/v1"), Some(1));
+		assert_eq!(port(&router, None, "/other"), None);
+	}
+
+	#[test]
+	fn longest_prefix() {
+		let router = Router::new(vec![
+			route(None, "/", 1),
+			route(None, "/api/", 2),
+			route(None, "/api/admin", 3),
+			route(None, "/api", 4),
+			// ties go to the route listed first
+			route(None, "/api/admin", 5),
+		], None, Forwarding::default()).unwrap();
+
+		assert_eq!(port(&router, None, "/api/admin/users"), Some(3));
+		assert_eq!(port(&router, None, "/api/administrators"), Some(2));
+		assert_eq!(port(&router, None, "/api?q=1"), Some(2));
+		assert_eq!(port(&router, None, "/apiary"), Some(1));
+		assert_eq!(port(&router, None, "/"), Some(1));
+	}
+
+	#[test]
+	fn server_path() {
+		let router = Router::new(vec![ route(None, "/", 1) ], Some(2), Forwarding::default()).unwrap();
+
+		let destination = router.route(Some("example.com"), "/__atbws").unwrap();
+		assert_eq!(destination.port, SERVER_PORT);
+		assert!(!destination.forwarding.forwarded_headers);
+	}
+
+	#[test]
+	fn prefix_stripping() {
+		assert_eq!(strip_prefix("/api/users?x", "/api"), "/users?x");
+		assert_eq!(strip_prefix("/api/users", "/api/"), "/users");
+		assert_eq!(strip_prefix("/api", "/api"), "/");
+		assert_eq!(strip_prefix("/api?q", "/api"), "/?q");
+		assert_eq!(strip_prefix("/users", "/"), "/users");
+
+		let mut stripped = route(None, "/api", 1);
+		stripped.strip_prefix = true;
+		let router = Router::new(vec![ stripped, route(None, "/", 2) ], None, Forwarding::default()).unwrap();
+
+		assert_eq!(router.route(None, "/api/users").unwrap().target.as_deref(), Some("/users"));
+		assert_eq!(router.route(None, "/apiary").unwrap().target, None);
+	}
+
+	#[test]
+	fn ipv6_hosts() {
+		assert_eq!(without_port("[::1]:8080"), "[::1]");
+		assert_eq!(without_port("[2001:db8::1]"), "[2001:db8::1]");
+		assert_eq!(without_port("example.com:80"), "example.com");
+		assert_eq!(without_port("example.com"), "example.com");
+
+		let router = Router::new(vec![ route(Some("[::1]"), "/", 1) ], None, Forwarding::default()).unwrap();
+		assert_eq!(port(&router, Some("[::1]:3000"), "/"), Some(1));
+		assert_eq!(port(&router, Some("[::2]:3000"), "/"), None);
+	}
+
+	#[test]
+	fn default_port() {
+		let forwarding = Forwarding { forwarded_headers: false, proxy_protocol: None };
+		let router = Router::new(vec![ route(None, "/api", 1) ], None, forwarding).unwrap();
+
+		assert!(!router.is_empty());
+		assert_eq!(port(&router, None, "/other"), None);
+
+		assert_eq!(router.set_default_port(Some(2)), None);
+		assert_eq!(router.route(None, "/other"), Some(Destination { port: 2, target: None, forwarding }));
+		assert_eq!(router.fallback().map(|destination| destination.port), Some(2));
+
+		assert_eq!(router.set_default_port(None), Some(2));
+		assert_eq!(router.fallback(), None);
+		assert!(Router::new(Vec::new(), None, forwarding).unwrap().is_empty());
+	}
+
+	#[test]
+	fn bad_path() {
+		assert!(Router::new(vec![ route(None, "api", 1) ], None, Forwarding::default()).is_err());
+	}
+}
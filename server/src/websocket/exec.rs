@@ -0,0 +1,142 @@
+use super::{ shell, status, Input, Output, QUEUE_SIZE };
+use crate::config::ShellConfig;
+
+use autobahn_protocol::ChannelId;
+
+use std::io::{ self, ErrorKind };
+use std::os::unix::process::ExitStatusExt;
+use std::process::Stdio;
+use std::time::Duration;
+
+use tokio::io::{ AsyncRead, AsyncReadExt, AsyncWriteExt };
+use tokio::process::{ ChildStdin, Command };
+use tokio::sync::mpsc::{ self, Receiver, Sender };
+use tokio::sync::oneshot;
+use tokio::time;
+
+const BUFFER_SIZE: usize = 8192;
+/// How long output is still read for once the command has exited.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Runs `args` without a terminal, in the same directory and environment as a
+/// shell would get. Stdin is fed from `Input::Data` until `Input::Eof`, and
+/// stdout and stderr are sent back as `Output::Data` and `Output::ErrorData`.
+pub(super) fn handle_client(
+	config: &ShellConfig,
+	args: &[String],
+	client_env: &[(String, String)],
+	channel: ChannelId,
+	output_tx: Sender<(ChannelId, Output)>,
+) -> io::Result<Sender<Input>> {
+	let (program, args) = args.split_first()
+		.ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no command given"))?;
+
+	let mut command = Command::new(program);
+	command.args(args)
+		.env_clear()
+		.envs(shell::environment(config, None, client_env)?)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.kill_on_drop(true);
+	if let Some(ref cwd) = config.cwd {
+		command.current_dir(cwd);
+	}
+
+	let mut child = command.spawn()?;
+	let stdin = child.stdin.take();
+	let stdout = child.stdout.take();
+	let stderr = child.stderr.take();
+
+	let (input_tx, input_rx) = mpsc::channel(QUEUE_SIZE);
+	let (kill_tx, kill_rx) = oneshot::channel();
+
+	// a command that never reads its stdin mustn't hold up its output
+	tokio::spawn(write_input(stdin, input_rx, kill_tx));
+
+	tokio::spawn(async move {
+		let finished = async move {
+			let stdout = read_output(stdout, channel, output_tx.clone(), Output::Data);
+			let stderr = read_output(stderr, channel, output_tx.clone(), Output::ErrorData);
+			let readers = async { tokio::join!(stdout, stderr) };
+			tokio::pin!(readers);
+
+			// something the command left running in the background can hold its
+			// pipes open for as long as it likes, so the exit doesn't wait on them
+			let exited = tokio::select! {
+				_ = &mut readers => None,
+				status = child.wait() => Some(status),
+			};
+			let status = match exited {
+				Some(status) => {
+					// but whatever the command wrote before exiting still gets sent
+					let _ = time::timeout(DRAIN_TIMEOUT, &mut readers).await;
+					status
+				},
+				None => child.wait().await,
+			};
+
+			(status, output_tx)
+		};
+
+		tokio::select! {
+			(status, output_tx) = finished => match status {
+				Ok(status) => {
+					let _ = output_tx.send((channel, Output::Died(status::from_wait(status.into_raw())))).await;
+				},
+				Err(error) => {
+					warn!("failed to wait for command: {}", error);
+					let _ = output_tx.send((channel, Output::Closed)).await;
+				},
+			},
+			// dropping the child kills it
+			_ = kill_rx => (),
+		}
+	});
+
+	Ok(input_tx)
+}
+
+async fn write_input(
+	mut stdin: Option<ChildStdin>,
+	mut input_rx: Receiver<Input>,
+	kill_tx: oneshot::Sender<()>,
+) {
+	loop {
+		match input_rx.recv().await {
+			Some(Input::Data(data)) => if let Some(ref mut writer) = stdin {
+				// the command closed its stdin, so there's nowhere for the rest to go
+				if writer.write_all(&data).await.is_err() {
+					stdin = None;
+				}
+			},
+			Some(Input::Eof) => stdin = None,
+			Some(Input::End) | None => {
+				let _ = kill_tx.send(());
+				break
+			},
+			Some(_) => (),
+		}
+	}
+}
+
+async fn read_output(
+	pipe: Option<impl AsyncRead + Unpin>,
+	channel: ChannelId,
+	output_tx: Sender<(ChannelId, Output)>,
+	wrap: fn(Vec<u8>) -> Output,
+) {
+	let mut pipe = match pipe {
+		Some(pipe) => pipe,
+		None => return,
+	};
+	let mut buffer = vec![ 0; BUFFER_SIZE ];
+
+	while let Ok(read) = pipe.read(&mut buffer).await {
+		if read == 0 {
+			break
+		}
+
+		let _ = output_tx.send((channel, wrap(buffer[..read].to_vec()))).await;
+	}
+}
@@ -0,0 +1,233 @@
+use super::{ open_error, portfwd, send_data, Attachment, Client, Input, Output, QUEUE_SIZE };
+use crate::auth::{ CredentialId, Identity, Permissions };
+
+use autobahn_protocol::{ ChannelId, Connection, Message, Replay, SERVER_CHANNEL };
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+
+use tokio::sync::mpsc::{ self, Receiver, Sender, UnboundedReceiver, UnboundedSender };
+use tokio::sync::oneshot;
+use tokio::time;
+
+const TOKEN_LENGTH: usize = 16;
+/// How long a link is kept after its connection is lost.
+const RESUME_TIMEOUT: Duration = Duration::from_secs(300);
+/// How much output is kept for a lost connection before giving up on it.
+const REPLAY_LIMIT: usize = 8 << 20;
+
+/// A request to take a link over, answered with the link.
+pub(super) type Claim = oneshot::Sender<Link>;
+
+/// Everything about a client's session that outlives its connection, so
+/// that the client can resume it after reconnecting.
+#[derive(Debug)]
+pub(super) struct Link {
+	pub(super) token: Vec<u8>,
+	pub(super) credential: CredentialId,
+	pub(super) permissions: Permissions,
+	pub(super) channels: HashMap<ChannelId, (Connection, Sender<Input>)>,
+	next_channel: ChannelId,
+	pub(super) output_tx: Sender<(ChannelId, Output)>,
+	pub(super) output_rx: Receiver<(ChannelId, Output)>,
+	/// Whoever holds the link hands it over to these.
+	pub(super) claims: UnboundedReceiver<Claim>,
+	pub(super) replay: Replay,
+}
+
+impl Link {
+	/// Sends a message, keeping it to send again on resuming until the
+	/// client acknowledges it.
+	pub(super) async fn send(&mut self, client: &mut Client, message: Message) -> io::Result<()> {
+		send_data(client, self.replay.record(&message)).await
+	}
+
+	/// What to tell the client about output on one of its channels.
+	pub(super) fn output(&mut self, channel: ChannelId, output: Output) -> Vec<Message> {
+		let connection = match self.channels.get(&channel) {
+			Some((connection, _)) => connection,
+			None => return Vec::new(),
+		};
+
+		match output {
+			Output::Data(data) => vec![ match connection {
+				Connection::Shell(_) | Connection::Attach(_) => Message::TerminalOutput(channel, data),
+				_ => Message::SocketOutput(channel, data),
+			} ],
+			Output::ErrorData(data) => vec![ Message::ErrorOutput(channel, data) ],
+			Output::Died(exit) => {
+				self.channels.remove(&channel);
+				vec![ Message::ChildDeath(channel, exit), Message::ChannelClose(channel) ]
+			},
+			Output::Session(session) => vec![ Message::SessionAttached(channel, session) ],
+			Output::Eof => vec![ Message::OutputEnd(channel) ],
+			Output::Closed => {
+				self.channels.remove(&channel);
+				vec![ Message::ChannelClose(channel) ]
+			},
+			Output::Accepted(stream) => {
+				let port = match *connection {
+					Connection::ReversePort(port) => port,
+					_ => return Vec::new(),
+				};
+
+				let accepted = SERVER_CHANNEL | self.next_channel;
+				self.next_channel = (self.next_channel + 1) & !SERVER_CHANNEL;
+
+				let input_tx = portfwd::relay(stream, accepted, self.output_tx.clone());
+				self.channels.insert(accepted, (Connection::Port(port), input_tx));
+				vec![ Message::ChannelAccept(channel, accepted) ]
+			},
+			Output::Opened => vec![ Message::ChannelOpened(channel) ],
+			Output::Failed(error) => {
+				warn!("failed to open {} on channel {}: {}", connection, channel, error);
+
+				let (code, reason) = open_error(connection, &error);
+				self.channels.remove(&channel);
+				vec![ Message::ChannelError(channel, code, reason), Message::ChannelClose(channel) ]
+			},
+		}
+	}
+}
+
+/// The links of every client, by the token they can be resumed with.
+#[derive(Debug, Default)]
+pub(super) struct Links {
+	links: Mutex<HashMap<Vec<u8>, Registration>>,
+}
+
+#[derive(Debug)]
+struct Registration {
+	credential: CredentialId,
+	claims: UnboundedSender<Claim>,
+}
+
+impl Links {
+	/// Starts a link for a client that just authenticated.
+	pub(super) fn register(&self, identity: Identity) -> Link {
+		let token = rand::random::<[u8; TOKEN_LENGTH]>().to_vec();
+		let (claim_tx, claims) = mpsc::unbounded_channel();
+		let (output_tx, output_rx) = mpsc::channel(QUEUE_SIZE);
+
+		self.links.lock().unwrap().insert(token.clone(), Registration {
+			credential: identity.credential.clone(),
+			claims: claim_tx,
+		});
+
+		Link {
+			token,
+			credential: identity.credential,
+			permissions: identity.permissions,
+			channels: HashMap::new(),
+			next_channel: 0,
+			output_tx,
+			output_rx,
+			claims,
+			replay: Replay::new(),
+		}
+	}
+
+	/// Takes over the link with `token`, from the connection that has it or
+	/// from being parked. Only the credential that started it can.
+	pub(super) async fn claim(&self, token: &[u8], credential: &CredentialId) -> Option<Link> {
+		let (claim_tx, claim_rx) = oneshot::channel();
+
+		{
+			let links = self.links.lock().unwrap();
+			let registration = links.get(token)?;
+
+			if registration.credential != *credential {
+				return None
+			}
+
+			registration.claims.send(claim_tx).ok()?;
+		}
+
+		claim_rx.await.ok()
+	}
+
+	/// Keeps a link whose connection was lost for a while, collecting its
+	/// output for the client to resume it.
+	pub(super) fn park(self: &Arc<Self>, mut link: Link) {
+		let links = self.clone();
+
+		tokio::spawn(async move {
+			let timeout = time::sleep(RESUME_TIMEOUT);
+			tokio::pin!(timeout);
+
+			loop {
+				tokio::select! {
+					Some((channel, output)) = link.output_rx.recv() => {
+						for message in link.output(channel, output) {
+							link.replay.record(&message);
+						}
+
+						if link.replay.unacknowledged_size() > REPLAY_LIMIT {
+							warn!("too much output for a lost connection, not keeping it to resume");
+							break
+						}
+					},
+					Some(claim) = link.claims.recv() => match claim.send(link) {
+						Ok(()) => return,
+						Err(returned) => link = returned,
+					},
+					_ = &mut timeout => {
+						info!("lost connection was not resumed in time");
+						break
+					},
+				}
+			}
+
+			links.end(link);
+		});
+	}
+
+	/// Finishes with a link for good. Shells keep running for the client to
+	/// attach to again, but everything else is closed.
+	pub(super) fn end(&self, mut link: Link) {
+		self.links.lock().unwrap().remove(&link.token);
+
+		for (channel, (connection, input_tx)) in link.channels.drain() {
+			let input = match connection {
+				Connection::Shell(_) | Connection::Attach(_) =>
+					Input::Detach(Attachment { channel, output_tx: link.output_tx.clone() }),
+				_ => Input::End,
+			};
+
+			// a channel that's behind mustn't hold up the rest
+			tokio::spawn(async move {
+				let _ = input_tx.send(input).await;
+			});
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn identity(name: &str, key: u8) -> Identity {
+		Identity {
+			name: name.into(),
+			credential: CredentialId::PublicKey(vec![ key; 32 ]),
+			permissions: Permissions::default(),
+		}
+	}
+
+	#[tokio::test]
+	async fn claim_by_credential() {
+		let links = Arc::new(Links::default());
+		let link = links.register(identity("laptop", 1));
+		let token = link.token.clone();
+		links.park(link);
+
+		// the same name isn't the same key
+		assert!(links.claim(&token, &identity("laptop", 2).credential).await.is_none());
+		assert!(links.claim(b"not a token", &identity("laptop", 1).credential).await.is_none());
+
+		let claimed = links.claim(&token, &identity("desktop", 1).credential).await.unwrap();
+		assert_eq!(claimed.token, token);
+	}
+}
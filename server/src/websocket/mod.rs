@@ -1,244 +1,574 @@
 use crate::SERVER_PORT;
+use crate::auth::{ Authenticator, Identity, Permissions };
+use crate::config::ShellConfig;
+use crate::destination::Destinations;
+use crate::netstat::{ self, Filter, Protocol };
 
-mod message;
+mod exec;
+mod link;
 mod shell;
 mod portfwd;
+mod sessions;
+mod status;
 
-use message::{ Connection, Message };
+use link::{ Claim, Link, Links };
+use sessions::{ Attachment, Sessions };
 
-use std::thread;
-use std::io::{ self, Error, ErrorKind };
-use std::sync::mpsc::Receiver;
+use autobahn_protocol::{
+	auth, Connection, ErrorCode, ExitStatus, Handshake, HandshakeError, Listener, Message, SessionId,
+	Transport, PROTOCOL, SERVER_CHANNEL, VERSION,
+};
 
-use websocket::OwnedMessage;
-use websocket::sync::{ stream, Client, Server };
+use std::io::{ self, ErrorKind };
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
 
-const PROTOCOL: &str = "autobahn-websocket-tunnel";
-const VERSION: (u8, u8) = (0, 2);
+use futures_util::{ SinkExt, StreamExt };
 
-pub fn start(key: &str, signaler: Receiver<()>) -> io::Result<()> {
+use tokio::net::{ TcpListener, TcpStream };
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio::time::{ self, Instant };
+
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::tungstenite::handshake::server::{ ErrorResponse, Request, Response };
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+type Client = WebSocketStream<TcpStream>;
+
+/// How long a client can go without sending anything, pings included,
+/// before its connection counts as lost.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How many messages a channel's input, or a link's output, holds before
+/// whoever sends more has to wait, so that a slow reader slows the writer
+/// down rather than filling up memory.
+const QUEUE_SIZE: usize = 64;
+
+pub async fn start(
+	authenticator: Arc<Authenticator>,
+	destinations: Arc<Destinations>,
+	shell: Arc<ShellConfig>,
+	mut signaler: oneshot::Receiver<()>,
+) -> io::Result<()> {
 	info!("server running");
 
 	let address = format!("0.0.0.0:{}", SERVER_PORT);
-	let mut server = Server::bind(address.as_str())?;
+	let listener = TcpListener::bind(address.as_str()).await?;
+	let sessions = Arc::new(Sessions::default());
+	let links = Arc::new(Links::default());
 
-	server.set_nonblocking(true)?;
-	
 	loop {
-		if let Ok(request) = server.accept() {
-			trace!("request received");
-
-			let key = String::from(key);
-			thread::spawn(move || {
-				let mut client = match request.use_protocol(PROTOCOL).accept() {
-					Ok(client) => client,
-					_ => return,
-				};
+		tokio::select! {
+			accepted = listener.accept() => if let Ok((stream, _)) = accepted {
+				trace!("request received");
 
-				if handle_client(&mut client, &key).is_err() {
-					warn!("client handler failed");
-					let _ = client.shutdown();
-				} else {
-					trace!("new connection finished");
-				}
-			});
-		}
+				let authenticator = authenticator.clone();
+				let destinations = destinations.clone();
+				let shell = shell.clone();
+				let sessions = sessions.clone();
+				let links = links.clone();
+				tokio::spawn(async move {
+					let mut client = match tokio_tungstenite::accept_hdr_async(stream, use_protocol).await {
+						Ok(client) => client,
+						_ => return,
+					};
 
-		if signaler.try_recv().is_ok() { break }
+					if handle_client(&mut client, &authenticator, &destinations, &shell, &sessions, &links).await.is_err() {
+						warn!("client handler failed");
+						let _ = client.close(None).await;
+					} else {
+						trace!("new connection finished");
+					}
+				});
+			},
+			_ = &mut signaler => break,
+		}
 	}
 
 	Ok(())
 }
 
-fn handle_client(
-	client: &mut Client<stream::TcpStream>,
-	key: &str,
+// the signature is fixed by tungstenite's handshake callback
+#[allow(clippy::result_large_err)]
+fn use_protocol(_: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+	response.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(PROTOCOL));
+	Ok(response)
+}
+
+async fn handle_client(
+	client: &mut Client,
+	authenticator: &Authenticator,
+	destinations: &Arc<Destinations>,
+	shell: &ShellConfig,
+	sessions: &Arc<Sessions>,
+	links: &Arc<Links>,
 ) -> io::Result<()> {
-	client.set_nonblocking(true)?;
+	let identity = match authenticate(client, authenticator).await? {
+		Some(identity) => identity,
+		None => return Ok(()),
+	};
+
+	let mut link = links.register(identity);
+
+	let result = async {
+		send(client, Message::ResumeToken(link.token.clone())).await?;
+		serve(client, &mut link, destinations, shell, sessions, links).await
+	}.await;
 
-	let mut state = ConnectionState::AwaitingHandshake;
-	let mut io = None;
+	// failing to send is as good as losing the connection
+	let (ending, result) = match result {
+		Ok(ending) => (ending, Ok(())),
+		Err(error) => (Ending::Lost, Err(error)),
+	};
+
+	match ending {
+		Ending::Finished => links.end(link),
+		Ending::Lost => {
+			info!("connection lost, keeping its session for the client to resume");
+			links.park(link);
+		},
+		Ending::Claimed(claim) => if let Err(link) = claim.send(link) {
+			links.park(link);
+		},
+	}
+
+	let _ = client.close(None).await;
+
+	result
+}
+
+// goes through the handshake, until the client is authenticated or leaves
+async fn authenticate(client: &mut Client, authenticator: &Authenticator) -> io::Result<Option<Identity>> {
+	let mut handshake = Handshake::new();
+	let mut nonce = Vec::new();
 
 	loop {
-		if let Ok(message) = client.recv_message() {
-			match message {
-				OwnedMessage::Close(_) => {
-					/* if state != ConnectionState::SessionEnded {
-						warn!("connection closed without wave");
-
-						if let Some((input, _)) = io {
-							input.send(Input::End);
-						}
-					} */
+		let message = match client.next().await {
+			None | Some(Err(_)) | Some(Ok(WsMessage::Close(_))) => return Ok(None),
+			Some(Ok(WsMessage::Binary(data))) => match minicbor::decode(data.as_slice()) {
+				Ok(message) => message,
+				Err(_) => {
+					malformed(client).await?;
+					return Ok(None)
+				},
+			},
+			Some(Ok(_)) => continue,
+		};
 
-					break
+		match message {
+			Message::Hello(_, _) => match handshake.observe(&message) {
+				Ok(_) => {
+					nonce = rand::random::<[u8; auth::NONCE_LENGTH]>().to_vec();
+					challenge(client, &mut handshake, &nonce).await?;
 				},
-				OwnedMessage::Ping(data) => {
-					debug!("websocket pinged");
-					client.send_message(&OwnedMessage::Pong(data))
-						.map_err(|_| Error::from(ErrorKind::Other))?;
+				Err(HandshakeError::VersionMismatch(major, minor)) => {
+					warn!("client speaks unsupported protocol {}.{}", major, minor);
+
+					let reason = format!(
+						"protocol version {}.{} is not supported, please upgrade to {}.{}",
+						major, minor, VERSION.0, VERSION.1,
+					);
+
+					send(client, Message::Error(ErrorCode::VersionMismatch, reason.clone())).await?;
+					let _ = client.close(Some(CloseFrame {
+						code: CloseCode::Protocol,
+						reason: reason.into(),
+					})).await;
+
+					return Ok(None)
 				},
-				OwnedMessage::Binary(data) => {
-					if let Ok(message) = minicbor::decode(data.as_slice()) {
-						match message {
-							Message::Authenticate(password) => {
-								if state == ConnectionState::AwaitingAuthentication {
-									let success = password == key;
-
-									client.send_message(
-										&OwnedMessage::Binary(
-											minicbor::to_vec(Message::Authentication(success))
-												.unwrap()
-										)
-									).map_err(|_| Error::from(ErrorKind::Other))?;
-
-									if success {
-										state = ConnectionState::AwaitingConnection;
-									}
-								}
-							},
-							Message::ConnectionType(connection) => {
-								if state == ConnectionState::AwaitingConnection {
-									if let Connection::Port(port) = connection {
-										if let Ok(handler_io) = portfwd::handle_client(port) {
-											io = Some(handler_io);
-											state = ConnectionState::SocketActive;
-										} else {
-											let _ = client.send_message(
-												&OwnedMessage::Binary(
-													minicbor::to_vec(Message::Error).unwrap()
-												)
-											);
-										}
-									} else {
-										if let Ok(handler_io) = shell::handle_client() {
-											io = Some(handler_io);
-											state = ConnectionState::ShellActive;
-										} else {
-											client.send_message(
-												&OwnedMessage::Binary(
-													minicbor::to_vec(Message::Error).unwrap()
-												)
-											).map_err(|_| Error::from(ErrorKind::Other))?;
-										}
-									}
-								}
-							},
-							Message::EndSession => {
-								if
-									state == ConnectionState::ShellActive ||
-									state == ConnectionState::SocketActive
-								{
-									let _ = io.unwrap().0.send(Input::End);
-								}
-								
-								break
+				Err(_) => (),
+			},
+			Message::Authenticate(ref credential) if handshake.observe(&message).is_ok() => {
+				let identity = authenticator.authenticate(&nonce, credential);
+				let reply = Message::Authentication(identity.is_some());
+				let _ = handshake.observe(&reply);
+
+				send(client, reply).await?;
+
+				if let Some(identity) = identity {
+					info!("client authenticated with {}", identity.name);
+					return Ok(Some(identity))
+				}
+
+				warn!("client failed authentication");
+
+				nonce = rand::random::<[u8; auth::NONCE_LENGTH]>().to_vec();
+				challenge(client, &mut handshake, &nonce).await?;
+			},
+			Message::EndSession => return Ok(None),
+			_ => (),
+		}
+	}
+}
+
+// how a connection stopped serving its link
+enum Ending {
+	/// The client ended the session, or broke the protocol.
+	Finished,
+	/// The connection dropped, and the client may resume on another.
+	Lost,
+	/// Another connection resumed the session.
+	Claimed(Claim),
+}
+
+async fn serve(
+	client: &mut Client,
+	link: &mut Link,
+	destinations: &Arc<Destinations>,
+	shell: &ShellConfig,
+	sessions: &Arc<Sessions>,
+	links: &Arc<Links>,
+) -> io::Result<Ending> {
+	let timeout = time::sleep(CLIENT_TIMEOUT);
+	tokio::pin!(timeout);
+
+	loop {
+		tokio::select! {
+			message = client.next() => {
+				timeout.as_mut().reset(Instant::now() + CLIENT_TIMEOUT);
+
+				let message = match message {
+					None | Some(Err(_)) | Some(Ok(WsMessage::Close(_))) => return Ok(Ending::Lost),
+					Some(Ok(WsMessage::Binary(data))) => match minicbor::decode(data.as_slice()) {
+						Ok(message) => message,
+						Err(_) => {
+							malformed(client).await?;
+							return Ok(Ending::Finished)
+						},
+					},
+					Some(Ok(_)) => continue,
+				};
+
+				if let Some(ack) = link.replay.receive(&message) {
+					send(client, ack).await?;
+				}
+
+				match message {
+					Message::EndSession => return Ok(Ending::Finished),
+					Message::Ack(received) => if let Err(error) = link.replay.acknowledge(received) {
+						warn!("client acknowledged what it couldn't have: {}", error);
+
+						send(client, Message::Error(ErrorCode::InvalidMessage, error.to_string())).await?;
+						return Ok(Ending::Finished)
+					},
+					Message::Resume(token, received) => {
+						// only a connection that hasn't been used yet can take over
+						if link.replay.received() != 0 {
+							warn!("client tried to resume a session in the middle of another");
+							continue
+						}
+
+						let resumed = match links.claim(&token, &link.credential).await {
+							Some(resumed) => resumed,
+							None => {
+								warn!("client tried to resume a session that isn't there");
+
+								send(client, Message::Error(
+									ErrorCode::NotFound,
+									"the session has expired and can't be resumed".into(),
+								)).await?;
+								return Ok(Ending::Finished)
 							},
-							Message::Hello(vmj, vmn) => {
-								if state == ConnectionState::AwaitingHandshake {
-									if (vmj, vmn) != VERSION {
-										client.send_message(
-											&OwnedMessage::Binary(
-												minicbor::to_vec(Message::Error).unwrap()
-											)
-										).map_err(|_| Error::from(ErrorKind::Other))?;
-
-										break
-									}
+						};
+
+						let unused = mem::replace(link, resumed);
+						link.permissions = unused.permissions.clone();
+						links.end(unused);
 
-									state = ConnectionState::AwaitingAuthentication;
+						let server_received = link.replay.received();
+						match link.replay.resume(received) {
+							Ok(unacknowledged) => {
+								info!("client resumed its session");
+
+								send(client, Message::Resumed(server_received)).await?;
+								for data in unacknowledged {
+									send_data(client, data.to_vec()).await?;
 								}
 							},
-							Message::SignalContinue => {
-								if state == ConnectionState::ShellActive {
-									let _ = io.as_ref().unwrap().0.send(Input::Continue);
-								}
+							Err(error) => {
+								warn!("client can't resume its session: {}", error);
+
+								send(client, Message::Error(ErrorCode::InvalidMessage, error.to_string())).await?;
+								return Ok(Ending::Finished)
 							},
-							Message::SignalStop => {
-								if state == ConnectionState::ShellActive {
-									let _ = io.as_ref().unwrap().0.send(Input::Stop);
-								}
+						}
+					},
+					Message::ChannelOpen(channel, connection) => {
+						if link.channels.contains_key(&channel) {
+							warn!("channel {} is already open", channel);
+							continue
+						}
+						if channel & SERVER_CHANNEL != 0 {
+							warn!("client tried to open server channel {}", channel);
+							continue
+						}
+
+						if !link.permissions.permits(&connection) {
+							warn!("denied {} on channel {}", connection, channel);
+							link.send(client, Message::ChannelError(
+								channel,
+								ErrorCode::PermissionDenied,
+								format!("permission denied for {}", connection),
+							)).await?;
+							link.send(client, Message::ChannelClose(channel)).await?;
+							continue
+						}
+
+						let output_tx = link.output_tx.clone();
+						let handler_io = match connection {
+							Connection::Port(port) =>
+								portfwd::handle_client(port, channel, output_tx).await,
+							Connection::Remote(ref host, port) =>
+								Ok(portfwd::handle_remote(host.clone(), port, destinations.clone(), channel, output_tx)),
+							Connection::UdpPort(port) =>
+								Ok(portfwd::handle_udp(port, channel, output_tx)),
+							Connection::ReversePort(port) =>
+								portfwd::handle_listen(port, channel, output_tx).await,
+							Connection::Shell(ref env) => {
+								let owner = link.credential.clone();
+								shell::handle_client(shell, env, sessions.clone(), owner, channel, output_tx)
 							},
-							Message::SignalWinch(w, h) => {
-								if state == ConnectionState::ShellActive {
-									let _ = io.as_ref().unwrap().0.send(Input::Winch(w, h));
-								}
+							Connection::Attach(session) => match sessions.find(session, &link.credential) {
+								Ok(input_tx) => {
+									let attachment = Attachment { channel, output_tx };
+									if send_input(client, link, &input_tx, Input::Attach(attachment)).await? {
+										Ok(input_tx)
+									} else {
+										Err(io::Error::new(ErrorKind::NotFound, format!("session {} has ended", session)))
+									}
+								},
+								Err(error) => Err(error),
 							},
-							Message::SocketInput(data) => {
-								if state == ConnectionState::SocketActive {
-									let _ = io.as_ref().unwrap().0.send(Input::Data(data));
+							Connection::Exec(ref args, ref env) =>
+								exec::handle_client(shell, args, env, channel, output_tx),
+						};
+
+						// these say whether they opened once they're set up
+						let background = matches!(connection, Connection::Remote(_, _) | Connection::UdpPort(_));
+
+						match handler_io {
+							Ok(input_tx) => {
+								link.channels.insert(channel, (connection, input_tx));
+								if !background {
+									link.send(client, Message::ChannelOpened(channel)).await?;
 								}
 							},
-							Message::TerminalInput(data) => {
-								if state == ConnectionState::ShellActive {
-									let _ = io.as_ref().unwrap().0.send(Input::Data(data));
-								}
+							Err(error) => {
+								warn!("failed to open {} on channel {}: {}", connection, channel, error);
+
+								let (code, reason) = open_error(&connection, &error);
+								link.send(client, Message::ChannelError(channel, code, reason)).await?;
+								link.send(client, Message::ChannelClose(channel)).await?;
 							},
-							_ => (),
 						}
-					}
-				},
-				_ => (),
-			}
-		}
+					},
+					Message::ListListeners(request) => {
+						let listeners = list_listeners(&link.permissions).await;
+						link.send(client, Message::Listeners(request, listeners)).await?;
+					},
+					Message::ListSessions(request) => {
+						// only those who could attach need to know
+						let list = if link.permissions.shell { sessions.list(&link.credential) } else { Vec::new() };
+						link.send(client, Message::Sessions(request, list)).await?;
+					},
+					Message::ChannelClose(channel) => {
+						if let Some((_, input_tx)) = link.channels.remove(&channel) {
+							send_input(client, link, &input_tx, Input::End).await?;
+						}
+					},
+					message => {
+						let channel = message.channel().unwrap_or_default();
+						let (connection, input_tx) = match link.channels.get(&channel) {
+							Some(entry) => entry,
+							None => continue,
+						};
 
-		if let Some((_, ref output)) = io {
-			if let Ok(data) = output.try_recv() {
-				client.send_message(
-					&OwnedMessage::Binary(
-						minicbor::to_vec(match data {
-							Output::Data(ref data) => match state {
-								ConnectionState::ShellActive =>
-									Message::TerminalOutput(data.clone()),
-								ConnectionState::SocketActive =>
-									Message::SocketOutput(data.clone()),
-								_ => continue,
-							},
-							Output::Died(exit) => match state {
-								ConnectionState::ShellActive =>
-									Message::ChildDeath(exit),
-								_ => continue,
-							},
-							Output::Closed => match state {
-								ConnectionState::SocketActive =>
-									Message::SocketClose,
-								_ => continue,
-							},
-						}).unwrap()
-					)
-				).map_err(|_| Error::from(ErrorKind::Other))?;
+						let input_tx = input_tx.clone();
+						let input = match (message, connection) {
+							(Message::SignalContinue(_), Connection::Shell(_) | Connection::Attach(_)) =>
+								Input::Continue,
+							(Message::SignalStop(_), Connection::Shell(_) | Connection::Attach(_)) =>
+								Input::Stop,
+							(Message::SignalWinch(_, w, h), Connection::Shell(_) | Connection::Attach(_)) =>
+								Input::Winch(w, h),
+							(
+								Message::SocketInput(_, data),
+								Connection::Port(_) | Connection::UdpPort(_) | Connection::Remote(_, _),
+							) => Input::Data(data),
+							(Message::TerminalInput(_, data), Connection::Shell(_) | Connection::Attach(_)) =>
+								Input::Data(data),
+							(Message::SocketInput(_, data), Connection::Exec(_, _)) =>
+								Input::Data(data),
+							(
+								Message::InputEnd(_),
+								Connection::Exec(_, _) | Connection::Port(_) | Connection::Remote(_, _),
+							) => Input::Eof,
+							_ => continue,
+						};
+
+						send_input(client, link, &input_tx, input).await?;
+					},
+				}
+			},
+			Some((channel, output)) = link.output_rx.recv() => {
+				for message in link.output(channel, output) {
+					link.send(client, message).await?;
+				}
+			},
+			Some(claim) = link.claims.recv() => return Ok(Ending::Claimed(claim)),
+			_ = &mut timeout => {
+				warn!("client went quiet");
+				return Ok(Ending::Lost)
+			},
+		}
+	}
+}
 
-				if let Output::Died(_) | Output::Closed = data {
-					break
+// waits for room in a channel's input, sending output on in the meantime, as
+// the channel may itself be waiting for room in the output. Returns whether
+// the channel was still there to take it.
+async fn send_input(client: &mut Client, link: &mut Link, input_tx: &Sender<Input>, input: Input) -> io::Result<bool> {
+	let permit = loop {
+		tokio::select! {
+			permit = input_tx.reserve() => match permit {
+				Ok(permit) => break permit,
+				Err(_) => return Ok(false),
+			},
+			Some((channel, output)) = link.output_rx.recv() => {
+				for message in link.output(channel, output) {
+					link.send(client, message).await?;
 				}
-			}
+			},
 		}
+	};
+
+	permit.send(input);
+	Ok(true)
+}
+
+// only the ports the client could forward to are its business
+async fn list_listeners(permissions: &Permissions) -> Vec<Listener> {
+	let socks = tokio::task::spawn_blocking(|| netstat::query(&Filter::listening(None))).await
+		.unwrap_or_default();
+
+	socks.into_iter()
+		.filter(|sock| permissions.ports.contains(sock.local_addr.port()))
+		.map(|sock| Listener {
+			address: sock.local_addr.ip(),
+			port: sock.local_addr.port(),
+			uid: sock.uid,
+			transport: match sock.protocol() {
+				Protocol::Tcp => Transport::Tcp,
+				Protocol::Udp => Transport::Udp,
+			},
+			pid: Some(sock.process.pid as u32),
+			process: Some(sock.process.name),
+		})
+		.collect()
+}
+
+// what to tell the client when a channel can't be opened
+fn open_error(connection: &Connection, error: &io::Error) -> (ErrorCode, String) {
+	match connection {
+		Connection::Port(port) | Connection::UdpPort(port) => (
+			ErrorCode::ConnectionRefused,
+			format!("failed to connect to port {}: {}", port, error),
+		),
+		Connection::Remote(_, _) if error.kind() == ErrorKind::PermissionDenied => (
+			ErrorCode::PermissionDenied,
+			format!("{} is not an allowed destination", connection),
+		),
+		Connection::Remote(_, _) => (
+			ErrorCode::ConnectionRefused,
+			format!("failed to connect to {}: {}", connection, error),
+		),
+		Connection::ReversePort(port) => (
+			ErrorCode::ListenFailed,
+			format!("failed to listen on port {}: {}", port, error),
+		),
+		Connection::Shell(_) => (
+			ErrorCode::SpawnFailed,
+			format!("failed to start shell: {}", error),
+		),
+		Connection::Exec(_, _) => (
+			ErrorCode::SpawnFailed,
+			format!("failed to run {}: {}", connection, error),
+		),
+		Connection::Attach(_) => (
+			ErrorCode::NotFound,
+			format!("failed to attach: {}", error),
+		),
 	}
+}
+
+async fn challenge(
+	client: &mut Client,
+	handshake: &mut Handshake,
+	nonce: &[u8],
+) -> io::Result<()> {
+	let message = Message::Challenge(nonce.to_vec());
+	let _ = handshake.observe(&message);
 
-	client.shutdown()
+	send(client, message).await
 }
 
-#[derive(Clone, Debug, PartialEq)]
+async fn malformed(client: &mut Client) -> io::Result<()> {
+	warn!("client sent a malformed message");
+
+	send(client, Message::Error(
+		ErrorCode::InvalidMessage,
+		"malformed or unknown message".into(),
+	)).await
+}
+
+async fn send(client: &mut Client, message: Message) -> io::Result<()> {
+	send_data(client, minicbor::to_vec(message).unwrap()).await
+}
+
+// a client that stops reading is as good as gone, and mustn't hold up whoever
+// resumes its session
+async fn send_data(client: &mut Client, data: Vec<u8>) -> io::Result<()> {
+	match time::timeout(CLIENT_TIMEOUT, client.send(WsMessage::Binary(data))).await {
+		Ok(Ok(())) => Ok(()),
+		Ok(Err(_)) => Err(ErrorKind::Other.into()),
+		Err(_) => Err(ErrorKind::TimedOut.into()),
+	}
+}
+
+#[derive(Clone, Debug)]
 enum Input {
 	Data(Vec<u8>),
 	Continue,
 	Stop,
 	Winch(u16, u16),
+	/// No more data is coming, though output is still wanted.
+	Eof,
+	/// Sends a shell's output to another channel from now on.
+	Attach(Attachment),
+	/// The channel is gone, but the shell should keep running.
+	Detach(Attachment),
 	End,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Debug)]
 enum Output {
 	Data(Vec<u8>),
-	Died(u8),
+	ErrorData(Vec<u8>),
+	Died(ExitStatus),
+	/// The session a shell channel is attached to.
+	Session(SessionId),
+	/// The socket has stopped giving data, though input is still wanted.
+	Eof,
 	Closed,
-}
-
-#[derive(Clone, Copy, Debug, PartialEq)]
-enum ConnectionState {
-	AwaitingHandshake,
-	AwaitingAuthentication,
-	AwaitingConnection,
-	ShellActive,
-	SocketActive,
+	/// A connection to a listening channel, which needs a channel of its own.
+	Accepted(TcpStream),
+	/// A channel set up in the background is ready.
+	Opened,
+	/// A channel set up in the background couldn't be.
+	Failed(io::Error),
 }
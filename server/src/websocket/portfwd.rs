@@ -1,44 +1,239 @@
-use super::{ Input, Output };
+use super::{ Input, Output, QUEUE_SIZE };
+use crate::destination::Destinations;
 
-use std::thread;
-use std::sync::mpsc::{ self, Receiver, Sender };
-use std::io::{ self, Read, Write };
-use std::net::{ Shutdown, TcpStream };
+use autobahn_protocol::ChannelId;
 
-pub(super) fn handle_client(
+use std::io::{ self, Error, ErrorKind };
+use std::net::{ IpAddr, SocketAddr };
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+use tokio::net::{ self as net, TcpListener, TcpStream, UdpSocket };
+use tokio::sync::mpsc::{ self, Receiver, Sender };
+use tokio::time;
+
+const BUFFER_SIZE: usize = 8192;
+// enough for any datagram
+const DATAGRAM_SIZE: usize = 65536;
+/// How long to wait after failing to accept a connection.
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+pub(super) async fn handle_client(
 	port: u16,
-) -> io::Result<(Sender<Input>, Receiver<Output>)> {
-	let mut stream = TcpStream::connect(format!("127.0.0.1:{}", port))?;
-
-	let (input_tx, input_rx) = mpsc::channel();
-	let (output_tx, output_rx) = mpsc::channel();
-
-	stream.set_nonblocking(true)?;
-
-	thread::spawn(move || loop {
-		let mut buffer = [ 0; 256 ];
-		if let Ok(read) = stream.read(&mut buffer) {
-			if read == 0 {
-				let _ = output_tx.send(Output::Closed);
-				break
-			} else {
-				let _ = output_tx.send(Output::Data(buffer[..read].to_vec()));
-			}
+	channel: ChannelId,
+	output_tx: Sender<(ChannelId, Output)>,
+) -> io::Result<Sender<Input>> {
+	let stream = TcpStream::connect(("127.0.0.1", port)).await?;
+
+	Ok(relay(stream, channel, output_tx))
+}
+
+/// Connects to a host outside the repl, if the allowlist permits any of the
+/// addresses it resolves to. Addresses of the repl itself, loopback and
+/// unspecified ones, bypass the allowlist and only need the port to be
+/// permitted, as with `handle_client`. The lookup and connection can take a
+/// while, so they happen in the background, ending in `Output::Opened` or
+/// `Output::Failed`.
+pub(super) fn handle_remote(
+	host: String,
+	port: u16,
+	destinations: Arc<Destinations>,
+	channel: ChannelId,
+	output_tx: Sender<(ChannelId, Output)>,
+) -> Sender<Input> {
+	let (input_tx, input_rx) = mpsc::channel(QUEUE_SIZE);
+
+	tokio::spawn(async move {
+		match connect_remote(&host, port, &destinations).await {
+			Ok(stream) => {
+				let _ = output_tx.send((channel, Output::Opened)).await;
+				forward(stream, channel, output_tx, input_rx).await;
+			},
+			Err(error) => {
+				let _ = output_tx.send((channel, Output::Failed(error))).await;
+			},
 		}
+	});
+
+	input_tx
+}
+
+async fn connect_remote(host: &str, port: u16, destinations: &Destinations) -> io::Result<TcpStream> {
+	let addresses: Vec<SocketAddr> = net::lookup_host((host, port)).await?
+		.filter(|address| is_local(address.ip()) || destinations.permits(host, *address))
+		.collect();
 
-		if let Ok(input) = input_rx.try_recv() {
-			match input {
-				Input::Data(data) => {
-					let _ = stream.write(data.as_slice());
+	if addresses.is_empty() {
+		return Err(Error::new(ErrorKind::PermissionDenied, "destination not allowed"))
+	}
+
+	TcpStream::connect(addresses.as_slice()).await
+}
+
+fn is_local(ip: IpAddr) -> bool {
+	let ip = ip.to_canonical();
+	ip.is_loopback() || ip.is_unspecified()
+}
+
+/// Relays between a channel and a connected stream, until either end closes.
+pub(super) fn relay(
+	stream: TcpStream,
+	channel: ChannelId,
+	output_tx: Sender<(ChannelId, Output)>,
+) -> Sender<Input> {
+	let (input_tx, input_rx) = mpsc::channel(QUEUE_SIZE);
+	tokio::spawn(forward(stream, channel, output_tx, input_rx));
+
+	input_tx
+}
+
+async fn forward(
+	stream: TcpStream,
+	channel: ChannelId,
+	output_tx: Sender<(ChannelId, Output)>,
+	mut input_rx: Receiver<Input>,
+) {
+	let (mut reader, mut writer) = stream.into_split();
+	let mut buffer = vec![ 0; BUFFER_SIZE ];
+	// each way can end on its own, and the channel closes once both have
+	let mut reading = true;
+	let mut writing = true;
+
+	loop {
+		tokio::select! {
+			read = reader.read(&mut buffer), if reading => match read {
+				Ok(0) if writing => {
+					let _ = output_tx.send((channel, Output::Eof)).await;
+					reading = false;
 				},
-				Input::End => {
-					let _ = stream.shutdown(Shutdown::Both);
+				Ok(0) | Err(_) => {
+					let _ = output_tx.send((channel, Output::Closed)).await;
 					break
 				},
-				_ => unreachable!(),
+				Ok(read) => {
+					let _ = output_tx.send((channel, Output::Data(buffer[..read].to_vec()))).await;
+				},
+			},
+			input = input_rx.recv() => match input {
+				Some(Input::Data(data)) => {
+					if writer.write_all(data.as_slice()).await.is_err() {
+						let _ = output_tx.send((channel, Output::Closed)).await;
+						break
+					}
+				},
+				Some(Input::Eof) => {
+					let _ = writer.shutdown().await;
+					writing = false;
+
+					if !reading {
+						let _ = output_tx.send((channel, Output::Closed)).await;
+						break
+					}
+				},
+				Some(Input::End) | None => {
+					let _ = writer.shutdown().await;
+					break
+				},
+				Some(_) => warn!("ignoring unexpected input on port channel {}", channel),
+			},
+		}
+	}
+}
+
+/// Relays datagrams between a channel and a UDP port. Each channel gets its own
+/// socket, so the server sees every peer of the client as a different address.
+/// Like `handle_remote`, it's set up in the background.
+pub(super) fn handle_udp(
+	port: u16,
+	channel: ChannelId,
+	output_tx: Sender<(ChannelId, Output)>,
+) -> Sender<Input> {
+	let (input_tx, mut input_rx) = mpsc::channel(QUEUE_SIZE);
+
+	tokio::spawn(async move {
+		let socket = match connect_udp(port).await {
+			Ok(socket) => socket,
+			Err(error) => {
+				let _ = output_tx.send((channel, Output::Failed(error))).await;
+				return
+			},
+		};
+		let _ = output_tx.send((channel, Output::Opened)).await;
+
+		let mut buffer = vec![ 0; DATAGRAM_SIZE ];
+
+		loop {
+			tokio::select! {
+				received = socket.recv(&mut buffer) => match received {
+					Ok(received) => {
+						let _ = output_tx.send((channel, Output::Data(buffer[..received].to_vec()))).await;
+					},
+					// nothing listening yet, which a later datagram may find different
+					Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => {
+						debug!("UDP port {} refused a datagram", port);
+					},
+					Err(_) => {
+						let _ = output_tx.send((channel, Output::Closed)).await;
+						break
+					},
+				},
+				input = input_rx.recv() => match input {
+					Some(Input::Data(data)) => {
+						if let Err(error) = socket.send(data.as_slice()).await {
+							debug!("failed to send datagram to UDP port {}: {}", port, error);
+						}
+					},
+					Some(Input::End) | None => break,
+					Some(_) => warn!("ignoring unexpected input on UDP channel {}", channel),
+				},
+			}
+		}
+	});
+
+	input_tx
+}
+
+async fn connect_udp(port: u16) -> io::Result<UdpSocket> {
+	let socket = UdpSocket::bind(("127.0.0.1", 0)).await?;
+	socket.connect(("127.0.0.1", port)).await?;
+
+	Ok(socket)
+}
+
+/// Listens on a port in the repl, handing each connection it accepts to the
+/// session to be given a channel of its own.
+pub(super) async fn handle_listen(
+	port: u16,
+	channel: ChannelId,
+	output_tx: Sender<(ChannelId, Output)>,
+) -> io::Result<Sender<Input>> {
+	let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+
+	let (input_tx, mut input_rx) = mpsc::channel(QUEUE_SIZE);
+
+	tokio::spawn(async move {
+		loop {
+			tokio::select! {
+				accepted = listener.accept() => match accepted {
+					Ok((stream, peer)) => {
+						debug!("accepted {} on port {}", peer, port);
+						let _ = output_tx.send((channel, Output::Accepted(stream))).await;
+					},
+					// errors like running out of file descriptors last a while, so
+					// trying again straight away would only spin
+					Err(error) => {
+						warn!("failed to accept on port {}: {}", port, error);
+						time::sleep(ACCEPT_BACKOFF).await;
+					},
+				},
+				input = input_rx.recv() => match input {
+					Some(Input::End) | None => break,
+					Some(_) => (),
+				},
 			}
 		}
 	});
 
-	Ok((input_tx, output_rx))
+	Ok(input_tx)
 }
@@ -0,0 +1,124 @@
+use super::{ Input, Output };
+use crate::auth::CredentialId;
+
+use autobahn_protocol::{ ChannelId, SessionId, ShellSession };
+
+use std::collections::HashMap;
+use std::io::{ self, ErrorKind };
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicU32, Ordering };
+use std::time::SystemTime;
+
+use tokio::sync::mpsc::Sender;
+
+/// The shells running on the server. They outlive the connections that start
+/// them, so that a client can detach and attach again later. Each belongs to
+/// the credential that started it, and nobody else can see or attach to it.
+#[derive(Debug, Default)]
+pub(super) struct Sessions {
+	sessions: Mutex<HashMap<SessionId, Session>>,
+	next_id: AtomicU32,
+}
+
+#[derive(Debug)]
+struct Session {
+	info: ShellSession,
+	owner: CredentialId,
+	input_tx: Sender<Input>,
+}
+
+impl Sessions {
+	pub(super) fn insert(
+		&self,
+		owner: CredentialId,
+		pid: u32,
+		command: String,
+		input_tx: Sender<Input>,
+	) -> SessionId {
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+
+		let started = SystemTime::now()
+			.duration_since(SystemTime::UNIX_EPOCH)
+			.map_or(0, |since| since.as_secs());
+
+		let info = ShellSession { id, pid, command, attached: true, started };
+		self.sessions.lock().unwrap().insert(id, Session { info, owner, input_tx });
+
+		id
+	}
+
+	/// The input of a session, for attaching to it, as long as `owner`
+	/// started it.
+	pub(super) fn find(&self, id: SessionId, owner: &CredentialId) -> io::Result<Sender<Input>> {
+		let sessions = self.sessions.lock().unwrap();
+		// someone else's session is as good as not there
+		let session = sessions.get(&id)
+			.filter(|session| session.owner == *owner)
+			.ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("no session {}", id)))?;
+
+		Ok(session.input_tx.clone())
+	}
+
+	pub(super) fn set_attached(&self, id: SessionId, attached: bool) {
+		if let Some(session) = self.sessions.lock().unwrap().get_mut(&id) {
+			session.info.attached = attached;
+		}
+	}
+
+	pub(super) fn remove(&self, id: SessionId) {
+		self.sessions.lock().unwrap().remove(&id);
+	}
+
+	pub(super) fn list(&self, owner: &CredentialId) -> Vec<ShellSession> {
+		let mut sessions: Vec<_> = self.sessions.lock().unwrap()
+			.values()
+			.filter(|session| session.owner == *owner)
+			.map(|session| session.info.clone())
+			.collect();
+		sessions.sort_by_key(|session| session.id);
+
+		sessions
+	}
+}
+
+/// The channel a session's output goes to while a client is attached.
+#[derive(Clone, Debug)]
+pub(super) struct Attachment {
+	pub(super) channel: ChannelId,
+	pub(super) output_tx: Sender<(ChannelId, Output)>,
+}
+
+impl Attachment {
+	pub(super) async fn send(&self, output: Output) {
+		let _ = self.output_tx.send((self.channel, output)).await;
+	}
+
+	// channel ids are only unique within one connection
+	pub(super) fn is(&self, other: &Self) -> bool {
+		self.channel == other.channel && self.output_tx.same_channel(&other.output_tx)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use tokio::sync::mpsc;
+
+	#[test]
+	fn owners() {
+		let sessions = Sessions::default();
+		let owner = CredentialId::PublicKey(vec![ 1; 32 ]);
+		let other = CredentialId::SharedKey;
+
+		let (input_tx, _input_rx) = mpsc::channel(1);
+		let id = sessions.insert(owner.clone(), 42, "/bin/sh".into(), input_tx.clone());
+
+		assert_eq!(sessions.list(&owner).iter().map(|session| session.pid).collect::<Vec<_>>(), [ 42 ]);
+		assert!(sessions.list(&other).is_empty());
+
+		let error = sessions.find(id, &other).unwrap_err();
+		assert_eq!(error.kind(), ErrorKind::NotFound);
+		assert!(sessions.find(id, &owner).unwrap().same_channel(&input_tx));
+	}
+}
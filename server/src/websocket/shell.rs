@@ -1,96 +1,285 @@
-use super::{ Input, Output };
+use super::{ status, Input, Output, QUEUE_SIZE };
+use super::sessions::{ Attachment, Sessions };
+use crate::auth::CredentialId;
+use crate::config::ShellConfig;
 
-use std::thread;
+use autobahn_protocol::{ ChannelId, ExitStatus };
+
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::{ CString, OsStr, OsString };
 use std::fs::File;
-use std::sync::mpsc::{ self, Receiver, Sender };
-use std::io::{ self, Read, Write };
-use std::os::unix::io::{ FromRawFd, RawFd };
+use std::io::{ self, ErrorKind, Read, Write };
+use std::os::unix::ffi::{ OsStrExt, OsStringExt };
+use std::os::unix::io::{ AsRawFd, FromRawFd, RawFd };
+use std::ptr::{ null, null_mut };
+use std::sync::Arc;
 
 use libc::{ SIGCONT, SIGSTOP, SIGWINCH, SIGKILL, TIOCSWINSZ };
 
-pub(super) fn handle_client() -> io::Result<(Sender<Input>, Receiver<Output>)> {
-	let (pty_fd, child_pid) = unsafe { launch_process("/bin/bash") }?;
-	let mut pty = unsafe { File::from_raw_fd(pty_fd) };
-	let mut pty_clone = pty.try_clone().map_err(|err| err)?;
+use tokio::io::unix::AsyncFd;
+use tokio::signal::unix::{ signal, SignalKind };
+use tokio::sync::mpsc::{ self, Sender };
+
+use vt100::Parser;
+
+const BUFFER_SIZE: usize = 8192;
+const DEFAULT_TERM: &str = "xterm-256color";
+// the size of a pty until the client says otherwise
+const DEFAULT_ROWS: u16 = 24;
+const DEFAULT_COLS: u16 = 80;
+
+/// Starts a shell as `config` says, with `client_env` from the client added
+/// to its environment last. The shell is registered in `sessions` as started
+/// by `owner`, and keeps running when its channel is detached until it exits
+/// or a channel attached to it is closed.
+pub(super) fn handle_client(
+	config: &ShellConfig,
+	client_env: &[(String, String)],
+	sessions: Arc<Sessions>,
+	owner: CredentialId,
+	channel: ChannelId,
+	output_tx: Sender<(ChannelId, Output)>,
+) -> io::Result<Sender<Input>> {
+	let command = Command::new(config, client_env)?;
+
+	// listen before forking, so that a child which dies immediately is seen
+	let mut child_signals = signal(SignalKind::child())?;
+
+	let (pty_fd, child_pid) = unsafe { launch_process(&command) }?;
+	let pty = Pty::new(unsafe { File::from_raw_fd(pty_fd) })?;
+
+	let (input_tx, mut input_rx) = mpsc::channel(QUEUE_SIZE);
+	let id = sessions.insert(owner, child_pid as u32, config.command.display().to_string(), input_tx.clone());
+
+	let attachment = Attachment { channel, output_tx };
+
+	tokio::spawn(async move {
+		attachment.send(Output::Session(id)).await;
+		let mut attachment = Some(attachment);
+
+		let mut buffer = vec![ 0; BUFFER_SIZE ];
+		let mut pty_open = true;
+		// what the terminal shows, for whoever attaches next
+		let mut screen = Parser::new(DEFAULT_ROWS, DEFAULT_COLS, 0);
+
+		loop {
+			tokio::select! {
+				read = pty.read(&mut buffer), if pty_open => match read {
+					Ok(read) if read > 0 => {
+						screen.process(&buffer[..read]);
+						if let Some(ref attachment) = attachment {
+							attachment.send(Output::Data(buffer[..read].to_vec())).await;
+						}
+					},
+					// the child hung up; wait for it to be reaped
+					_ => pty_open = false,
+				},
+				_ = child_signals.recv() => match unsafe { exit_status(child_pid) } {
+					Ok(Some(status)) => {
+						while let Ok(read) = pty.try_read(&mut buffer) {
+							if let Some(ref attachment) = attachment {
+								attachment.send(Output::Data(buffer[..read].to_vec())).await;
+							}
+						}
+
+						if let Some(ref attachment) = attachment {
+							attachment.send(Output::Died(status)).await;
+						}
+						break
+					},
+					Ok(None) => (),
+					Err(_) => warn!("failed to get child info"),
+				},
+				input = input_rx.recv() => match input {
+					Some(Input::Data(data)) => {
+						let _ = pty.write_all(&data).await;
+					},
+					Some(Input::Continue) => unsafe { libc::kill(child_pid, SIGCONT); },
+					Some(Input::Stop) => unsafe { libc::kill(child_pid, SIGSTOP); },
+					Some(Input::Winch(w, h)) => unsafe {
+						let size = libc::winsize {
+							ws_row: h,
+							ws_col: w,
+							ws_xpixel: 0,
+							ws_ypixel: 0,
+						};
+
+						if libc::ioctl(pty_fd, TIOCSWINSZ, &size) != -1 {
+							// a terminal that doesn't know its size says 0x0, which vt100 can't draw on
+							if w > 0 && h > 0 {
+								screen.set_size(h, w);
+							}
+							libc::kill(child_pid, SIGWINCH);
+						}
+					},
+					// a terminal's input only ends with ^D
+					Some(Input::Eof) => (),
+					Some(Input::Attach(new)) => {
+						if let Some(old) = attachment.replace(new.clone()) {
+							old.send(Output::Closed).await;
+						}
+
+						new.send(Output::Session(id)).await;
+						new.send(Output::Data(screen.screen().state_formatted())).await;
+						sessions.set_attached(id, true);
+					},
+					Some(Input::Detach(old)) => if attachment.as_ref().is_some_and(|current| current.is(&old)) {
+						attachment = None;
+						sessions.set_attached(id, false);
+
+						// it may have been stopped for the client's menu
+						unsafe { libc::kill(child_pid, SIGCONT); }
+					},
+					Some(Input::End) | None => {
+						unsafe { libc::kill(child_pid, SIGKILL); }
+						tokio::task::spawn_blocking(move || unsafe {
+							libc::waitpid(child_pid, null_mut(), 0);
+						});
+
+						break
+					},
+				},
+			}
+		}
+
+		sessions.remove(id);
+	});
 
-	let (input_tx, input_rx) = mpsc::channel();
-	let (output_tx, output_rx) = mpsc::channel();
+	Ok(input_tx)
+}
 
-	let (write_tx, write_rx) = mpsc::channel::<Vec<u8>>();
-	let (read_tx, read_rx) = mpsc::channel::<Vec<u8>>();
+// the master side of a pty, registered with the runtime
+struct Pty(AsyncFd<File>);
 
-	thread::spawn(move || loop {
-		let mut buffer = [ 0; 256 ];
-		if let Ok(read) = pty.read(&mut buffer) {
-			if read == 0 {
-				break
-			} else {
-				let _ = read_tx.send(buffer[..read].to_vec());
+impl Pty {
+	fn new(file: File) -> io::Result<Self> {
+		unsafe {
+			let flags = libc::fcntl(file.as_raw_fd(), libc::F_GETFL);
+			if flags == -1 || libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) == -1 {
+				return Err(io::Error::last_os_error())
 			}
 		}
-	});
 
-	thread::spawn(move || loop {
-		if let Ok(data) = write_rx.recv() {
-			let _ = pty_clone.write(&data);
-		}
-	});
+		Ok(Self(AsyncFd::new(file)?))
+	}
 
-	thread::spawn(move || loop {
-		if let Ok(data) = read_rx.try_recv() {
-			let _ = output_tx.send(Output::Data(data));
+	async fn read(&self, buffer: &mut [u8]) -> io::Result<usize> {
+		loop {
+			let mut guard = self.0.readable().await?;
+			if let Ok(result) = guard.try_io(|pty| pty.get_ref().read(buffer)) {
+				return result
+			}
 		}
+	}
 
-		if let Ok(exit) = unsafe { exit_status(child_pid) } {
-			if let Some(status) = exit {
-				let _ = output_tx.send(Output::Died(status));
+	fn try_read(&self, buffer: &mut [u8]) -> io::Result<usize> {
+		match self.0.get_ref().read(buffer) {
+			Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+			result => result,
+		}
+	}
 
-				break
+	async fn write_all(&self, mut data: &[u8]) -> io::Result<()> {
+		while !data.is_empty() {
+			let mut guard = self.0.writable().await?;
+			if let Ok(result) = guard.try_io(|pty| pty.get_ref().write(data)) {
+				data = &data[result?..];
 			}
-		} else {
-			warn!("failed to get child info");
 		}
 
-		if let Ok(input) = input_rx.try_recv() {
-			match input {
-				Input::Data(data) => {
-					let _ = write_tx.send(data);
-				},
-				Input::Continue => unsafe { libc::kill(child_pid, SIGCONT); },
-				Input::Stop => unsafe { libc::kill(child_pid, SIGSTOP); },
-				Input::Winch(w, h) => unsafe {
-					let size = libc::winsize {
-						ws_row: h,
-						ws_col: w,
-						ws_xpixel: 0,
-						ws_ypixel: 0,
-					};
-
-					if libc::ioctl(pty_fd, TIOCSWINSZ, &size) != -1 {
-						libc::kill(child_pid, SIGWINCH);
-					}
-				},
-				Input::End => unsafe {
-					libc::kill(child_pid, SIGKILL);
+		Ok(())
+	}
+}
 
-					break
-				},
+// everything the child needs, as anything allocated has to happen before the fork
+struct Command {
+	path: CString,
+	args: Vec<CString>,
+	env: Vec<CString>,
+	cwd: Option<CString>,
+}
+
+impl Command {
+	fn new(config: &ShellConfig, client_env: &[(String, String)]) -> io::Result<Self> {
+		let path = config.command.as_os_str();
+
+		// a login shell is told so by a `-` in front of its name
+		let name = if config.login {
+			let mut name = OsString::from("-");
+			name.push(config.command.file_name().unwrap_or(path));
+			name
+		} else {
+			path.to_owned()
+		};
+
+		let vars = environment(config, Some(DEFAULT_TERM), client_env)?;
+
+		if let Some(ref cwd) = config.cwd {
+			if !cwd.is_dir() {
+				return Err(io::Error::new(ErrorKind::NotFound, format!("{} is not a directory", cwd.display())))
 			}
 		}
-	});
 
-	Ok((input_tx, output_rx))
+		Ok(Self {
+			path: c_string(path)?,
+			args: Some(name.as_os_str())
+				.into_iter()
+				.chain(config.args.iter().map(OsStr::new))
+				.map(c_string)
+				.collect::<io::Result<_>>()?,
+			env: vars.into_iter()
+				.map(|(mut name, value)| {
+					name.push("=");
+					name.push(value);
+					c_string(&name)
+				})
+				.collect::<io::Result<_>>()?,
+			cwd: config.cwd.as_deref().map(|cwd| c_string(cwd.as_os_str())).transpose()?,
+		})
+	}
+}
+
+/// The variables a child of the server starts with: those inherited from the
+/// server as `config` allows, then `term` as `TERM`, then the variables from
+/// `config` and finally `client_env`, each overriding the ones before.
+pub(super) fn environment(
+	config: &ShellConfig,
+	term: Option<&str>,
+	client_env: &[(String, String)],
+) -> io::Result<BTreeMap<OsString, OsString>> {
+	let mut vars: BTreeMap<OsString, OsString> = env::vars_os()
+		.filter(|(name, _)| name.to_str().is_some_and(|name| config.inherit_env.inherits(name)))
+		// the server's own terminal, if it has one, isn't the child's
+		.filter(|(name, _)| name != "TERM")
+		.collect();
+	vars.extend(term.map(|term| ("TERM".into(), term.into())));
+	vars.extend(config.env.iter().map(|(name, value)| (name.into(), value.into())));
+	vars.extend(client_env.iter().map(|(name, value)| (name.into(), value.into())));
+
+	if vars.keys().any(|name| name.is_empty() || name.as_bytes().contains(&b'=')) {
+		return Err(io::Error::new(ErrorKind::InvalidInput, "invalid environment variable name"))
+	}
+
+	Ok(vars)
 }
 
-unsafe fn launch_process(path: &str) -> io::Result<(RawFd, libc::pid_t)> {
-	use std::ffi::CStr;
+fn c_string(string: &OsStr) -> io::Result<CString> {
+	CString::new(string.to_owned().into_vec()).map_err(|_| io::Error::from(ErrorKind::InvalidInput))
+}
+
+unsafe fn launch_process(command: &Command) -> io::Result<(RawFd, libc::pid_t)> {
 	use std::os::raw::c_ulong;
-	use std::ptr::null;
 
 	use libc::{ O_NOCTTY, O_RDWR, TIOCSCTTY };
 
 	const TIOCNOTTY: c_ulong = 0x5422; // for some reason this isn't in libc
 
+	// anything allocated has to happen before the fork
+	let args: Vec<_> = command.args.iter().map(|arg| arg.as_ptr()).chain([ null() ]).collect();
+	let env: Vec<_> = command.env.iter().map(|var| var.as_ptr()).chain([ null() ]).collect();
+	let ctty_file = CString::new("/dev/tty").unwrap();
+
 	let pty_master = libc::posix_openpt(O_NOCTTY | O_RDWR);
 	if pty_master == -1 {
 		return Err(io::Error::last_os_error())
@@ -109,7 +298,8 @@ unsafe fn launch_process(path: &str) -> io::Result<(RawFd, libc::pid_t)> {
 		return Err(io::Error::last_os_error())
 	}
 
-	let pty_slave = libc::open(pty_slave_path, O_RDWR);
+	// without O_NOCTTY, a server with no terminal of its own would take this one
+	let pty_slave = libc::open(pty_slave_path, O_NOCTTY | O_RDWR);
 	if pty_slave == -1 {
 		return Err(io::Error::last_os_error())
 	}
@@ -118,64 +308,52 @@ unsafe fn launch_process(path: &str) -> io::Result<(RawFd, libc::pid_t)> {
 	if fork_result == -1 {
 		return Err(io::Error::last_os_error())
 	} else if fork_result == 0 {
-		let ctty_file = CStr::from_bytes_with_nul(b"/dev/tty\0").unwrap();
+		// never return into the server's code from the child
+		libc::close(pty_master);
 
 		let pty_current = libc::open(ctty_file.as_ptr(), O_NOCTTY | O_RDWR);
 		if pty_current != -1 {
 			if libc::ioctl(pty_current, TIOCNOTTY, 0) == -1 {
-				return Err(io::Error::last_os_error())
+				libc::_exit(1);
 			}
 
 			libc::close(pty_current);
 		}
 
-		if libc::setsid() == -1 {
-			return Err(io::Error::last_os_error())
+		if libc::setsid() == -1 || libc::ioctl(pty_slave, TIOCSCTTY, 0) == -1 {
+			libc::_exit(1);
 		}
 
-		if libc::ioctl(pty_slave, TIOCSCTTY, 0) == -1 {
-			return Err(io::Error::last_os_error())
+		if libc::dup2(pty_slave, 0) == -1 || libc::dup2(pty_slave, 1) == -1 || libc::dup2(pty_slave, 2) == -1 {
+			libc::_exit(1);
 		}
 
-		if libc::dup2(pty_slave, 0) == -1 {
-			return Err(io::Error::last_os_error())
-		}
-		if libc::dup2(pty_slave, 1) == -1 {
-			return Err(io::Error::last_os_error())
-		}
-		if libc::dup2(pty_slave, 2) == -1 {
-			return Err(io::Error::last_os_error())
-		}
+		libc::close(pty_slave);
 
-		libc::close(pty_current);
+		if let Some(ref cwd) = command.cwd {
+			if libc::chdir(cwd.as_ptr()) == -1 {
+				libc::_exit(1);
+			}
+		}
 
-		let path_nullterm = [ path.as_bytes(), &[ 0 ] ].concat();
-		let path = CStr::from_bytes_with_nul(&path_nullterm).unwrap();
-		let env_nullterm = [ "TERM=xterm-256color".as_bytes(), &[ 0 ] ].concat();
-		let env = CStr::from_bytes_with_nul(&env_nullterm).unwrap();
-		libc::execve(
-			path.as_ptr(),
-			[ path.as_ptr(), null() ].as_ptr(),
-			[ env.as_ptr(), null() ].as_ptr(), // fix this pls
-		);
+		libc::execve(command.path.as_ptr(), args.as_ptr(), env.as_ptr());
 
-		return Err(io::Error::last_os_error())
+		libc::_exit(127);
 	};
 
+	// only the child should hold the slave open, so reads fail once it's gone
+	libc::close(pty_slave);
+
 	Ok((pty_master, fork_result))
 }
 
-unsafe fn exit_status(pid: libc::pid_t) -> io::Result<Option<u8>> {
+unsafe fn exit_status(pid: libc::pid_t) -> io::Result<Option<ExitStatus>> {
 	use libc::WNOHANG;
 
 	let mut status = 0;
 	match libc::waitpid(pid, &mut status, WNOHANG) {
 		-1 => Err(io::Error::last_os_error()),
 		0 => Ok(None),
-		_ => if libc::WIFEXITED(status) {
-			Ok(Some(libc::WEXITSTATUS(status) as u8))
-		} else {
-			Ok(Some(255)) // we probably need a better mechanism for this
-		},
+		_ => Ok(Some(status::from_wait(status))),
 	}
 }
@@ -0,0 +1,61 @@
+use autobahn_protocol::ExitStatus;
+
+use libc::c_int;
+
+/// Describes a status from `waitpid` of a child that finished.
+pub(super) fn from_wait(status: c_int) -> ExitStatus {
+	if libc::WIFSIGNALED(status) {
+		let signal = libc::WTERMSIG(status);
+
+		ExitStatus::Signaled {
+			signal: signal as u8,
+			name: signal_name(signal),
+			core_dumped: libc::WCOREDUMP(status),
+		}
+	} else {
+		ExitStatus::Exited(libc::WEXITSTATUS(status) as u8)
+	}
+}
+
+fn signal_name(number: c_int) -> String {
+	use libc::*;
+
+	let name = match number {
+		SIGHUP => "SIGHUP",
+		SIGINT => "SIGINT",
+		SIGQUIT => "SIGQUIT",
+		SIGILL => "SIGILL",
+		SIGTRAP => "SIGTRAP",
+		SIGABRT => "SIGABRT",
+		SIGBUS => "SIGBUS",
+		SIGFPE => "SIGFPE",
+		SIGKILL => "SIGKILL",
+		SIGUSR1 => "SIGUSR1",
+		SIGSEGV => "SIGSEGV",
+		SIGUSR2 => "SIGUSR2",
+		SIGPIPE => "SIGPIPE",
+		SIGALRM => "SIGALRM",
+		SIGTERM => "SIGTERM",
+		SIGSTKFLT => "SIGSTKFLT",
+		SIGCHLD => "SIGCHLD",
+		SIGCONT => "SIGCONT",
+		SIGSTOP => "SIGSTOP",
+		SIGTSTP => "SIGTSTP",
+		SIGTTIN => "SIGTTIN",
+		SIGTTOU => "SIGTTOU",
+		SIGURG => "SIGURG",
+		SIGXCPU => "SIGXCPU",
+		SIGXFSZ => "SIGXFSZ",
+		SIGVTALRM => "SIGVTALRM",
+		SIGPROF => "SIGPROF",
+		SIGWINCH => "SIGWINCH",
+		SIGIO => "SIGIO",
+		SIGPWR => "SIGPWR",
+		SIGSYS => "SIGSYS",
+		number if (SIGRTMIN()..=SIGRTMAX()).contains(&number) =>
+			return format!("SIGRTMIN+{}", number - SIGRTMIN()),
+		number => return format!("signal {}", number),
+	};
+
+	name.into()
+}